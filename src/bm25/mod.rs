@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+// How quickly repeating a term stops counting for more
+const K1: f64 = 1.2;
+// How much longer documents are held back for having more terms to match
+const B: f64 = 0.75;
+
+// Too common to say anything about what a document is about
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "did", "do", "for",
+    "from", "had", "has", "have", "he", "her", "him", "his", "i", "if", "in", "is",
+    "it", "its", "me", "mc", "my", "of", "on", "or", "our", "she", "so", "that",
+    "the", "their", "them", "they", "this", "to", "was", "we", "were", "what",
+    "with", "you", "your"
+];
+
+// Lowercased words and numbers, without the stop words.
+pub fn tokenize( text: &str ) -> impl Iterator<Item = String> + '_ {
+    text.split(|character: char| !character.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+/*
+ An inverted index over short texts, ranked by Okapi BM25, for finding
+ the ones that mention the exact words searched for.
+
+ Like 'hnsw::Hnsw', documents are indexed by node id, and removed ones
+ are left as holes until 'renumber' is used.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Bm25 {
+    documents: Vec<Option<Document>>,
+    // The nodes each term appears in
+    postings: HashMap<String, Vec<usize>>,
+    total_length: usize,
+    document_count: usize
+}
+#[derive(Debug, Clone)]
+struct Document {
+    term_counts: HashMap<String, usize>,
+    length: usize
+}
+
+impl Bm25 {
+    pub fn insert( &mut self, node: usize, text: &str ) {
+        self.remove(node);
+
+        let mut term_counts: HashMap<String, usize> = HashMap::new();
+        let mut length = 0;
+        for term in tokenize(text) {
+            *term_counts.entry(term).or_insert(0) += 1;
+            length += 1;
+        }
+
+        for term in term_counts.keys() {
+            self.postings.entry(term.clone())
+                .or_default()
+                .push(node);
+        }
+        if self.documents.len() <= node {
+            self.documents.resize(node + 1, None);
+        }
+        self.documents[node] = Some(Document { term_counts, length });
+        self.total_length += length;
+        self.document_count += 1;
+    }
+    pub fn remove( &mut self, node: usize ) {
+        let Some(document) = self.documents.get_mut(node).and_then(Option::take) else {
+            return;
+        };
+
+        for term in document.term_counts.keys() {
+            if let Some(nodes) = self.postings.get_mut(term) {
+                nodes.retain(|posted| *posted != node);
+                if nodes.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_length -= document.length;
+        self.document_count -= 1;
+    }
+    // Moves each node to 'mapping[node]', dropping any mapped to None.
+    pub fn renumber( &mut self, mapping: &[Option<usize>] ) {
+        let documents = std::mem::take(&mut self.documents);
+        *self = Self::default();
+
+        for (old, document) in documents.into_iter().enumerate() {
+            let (Some(document), Some(Some(new))) = (document, mapping.get(old)) else {
+                continue;
+            };
+
+            for term in document.term_counts.keys() {
+                self.postings.entry(term.clone())
+                    .or_default()
+                    .push(*new);
+            }
+            if self.documents.len() <= *new {
+                self.documents.resize(*new + 1, None);
+            }
+            self.total_length += document.length;
+            self.document_count += 1;
+            self.documents[*new] = Some(document);
+        }
+    }
+    // The share of the query's terms a node contains, from 0 to 1.
+    pub fn coverage( &self, node: usize, query: &str ) -> f64 {
+        let Some(Some(document)) = self.documents.get(node) else {
+            return 0f64;
+        };
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
+        if terms.is_empty() {
+            return 0f64;
+        }
+
+        terms.iter().filter(|term| document.term_counts.contains_key(*term)).count() as f64 / terms.len() as f64
+    }
+    /*
+     The (up to) 'k' nodes that best match 'query', as (node, score) pairs,
+     best first.
+
+     Only nodes sharing at least one term with the query are returned.
+    */
+    pub fn search( &self, query: &str, k: usize ) -> Vec<(usize, f64)> {
+        if self.document_count == 0 {
+            return Vec::new();
+        }
+
+        let average_length = (self.total_length as f64 / self.document_count as f64).max(1f64);
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
+
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for term in &terms {
+            let Some(nodes) = self.postings.get(term) else {
+                continue;
+            };
+
+            let matching = nodes.len() as f64;
+            let idf = (1f64 + (self.document_count as f64 - matching + 0.5) / (matching + 0.5)).ln();
+            for &node in nodes {
+                let document = self.documents[node].as_ref().unwrap();
+                let frequency = document.term_counts[term] as f64;
+                let length_norm = 1f64 - B + B * document.length as f64 / average_length;
+
+                *scores.entry(node).or_insert(0f64) += idf * frequency * (K1 + 1f64) / (frequency + K1 * length_norm);
+            }
+        }
+
+        let mut ranked: Vec<(usize, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|(a_node, a_score), (b_node, b_score)| b_score.total_cmp(a_score).then(a_node.cmp(b_node)));
+        ranked.truncate(k);
+
+        ranked
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::PathBuf;
+use clap::{ Parser, Subcommand };
+
+use crate::config::{ Config, StorageBackend };
+
+/*
+ Command-line interface.
+
+ Without a subcommand, the REPL, web client, memory agent and autosave all run together.
+ Flags override both the config file and the environment.
+*/
+#[derive(Debug, Parser)]
+#[command(version, about = "A user-assistant model prompter with long-term memory")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config file to load, instead of 'data/config.toml'
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Where to keep the character, either 'json' or 'sqlite'
+    #[arg(long, global = true)]
+    pub storage: Option<StorageBackend>,
+
+    /// Character file to load and save, with the 'json' storage
+    #[arg(long, global = true)]
+    pub character: Option<PathBuf>,
+
+    /// Database to load and save, with the 'sqlite' storage
+    #[arg(long, global = true)]
+    pub database: Option<PathBuf>,
+
+    /// Address for the web client, such as '127.0.0.1:3000'
+    #[arg(long, global = true)]
+    pub bind: Option<String>,
+
+    /// Base URL of an OpenAI-compatible API
+    #[arg(long, global = true)]
+    pub base_url: Option<String>,
+
+    /// Model used for chatting
+    #[arg(long, global = true)]
+    pub chat_model: Option<String>,
+
+    /// Model used for memory checks, profiles and summaries
+    #[arg(long, global = true)]
+    pub completion_model: Option<String>,
+
+    /// Model used for memory embeddings
+    #[arg(long, global = true)]
+    pub embedding_model: Option<String>
+}
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Serve the web client only, without reading stdin (for running as a service)
+    Serve,
+    /// Chat through the REPL only, without the web client
+    Chat,
+    /// Print the character without embeddings, like the REPL's 'log' command
+    Inspect,
+    /// Write the character, embeddings included, to a file ('-' for stdout)
+    Export {
+        path: PathBuf
+    },
+    /// Print memories matching some text and/or made within a time range
+    Search {
+        /// Text to look for in the conversation, user profile or summary (case-insensitive)
+        #[arg(long)]
+        text: Option<String>,
+
+        /// Earliest time, in seconds since the Unix epoch
+        #[arg(long)]
+        from: Option<u64>,
+
+        /// Latest time, in seconds since the Unix epoch
+        #[arg(long)]
+        to: Option<u64>
+    },
+    /// Replace the character with one read from a JSON file
+    Import {
+        path: PathBuf,
+
+        /// Add the file's memories to the current character instead of replacing it
+        #[arg(long)]
+        merge: bool
+    },
+    /// Upgrade the saved character to the current schema, as also happens on every start
+    Migrate {
+        /// Only list the migrations that would run, without changing anything
+        #[arg(long)]
+        dry_run: bool
+    }
+}
+
+impl Cli {
+    pub fn apply( &self, config: &mut Config ) {
+        if let Some(storage) = self.storage { config.storage.backend = storage; }
+        if let Some(character) = &self.character { config.storage.character_path = character.clone(); }
+        if let Some(database) = &self.database { config.storage.database_path = database.clone(); }
+        if let Some(bind) = &self.bind { config.server.bind = bind.clone(); }
+        if let Some(base_url) = &self.base_url { config.openai.base_url = base_url.clone(); }
+        if let Some(chat_model) = &self.chat_model { config.openai.chat_model = chat_model.clone(); }
+        if let Some(completion_model) = &self.completion_model { config.openai.completion_model = completion_model.clone(); }
+        if let Some(embedding_model) = &self.embedding_model { config.openai.embedding_model = embedding_model.clone(); }
+    }
+}
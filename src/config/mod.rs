@@ -0,0 +1,309 @@
+use std::fmt;
+use std::path::{ Path, PathBuf };
+
+use crate::{ Serialize, Deserialize };
+use crate::env;
+use crate::openai;
+
+pub const DEFAULT_CONFIG_PATH: &str = "data/config.toml";
+
+/*
+ Runtime configuration for a Monikai.
+
+ Built up in layers, where each overrides the last:
+  1. The defaults below.
+  2. A TOML file, 'data/config.toml' unless another is given with '--config'.
+  3. Environment variables (see 'apply_env').
+  4. Command-line flags.
+
+ Any field missing from the file keeps its default.
+*/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub openai: OpenAiConfig,
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub timing: TimingConfig,
+    pub retrieval: RetrievalConfig,
+    pub prompt: PromptConfig
+}
+/*
+ The API and the models used for each role.
+
+ 'max_tokens' limits completions, such as summaries and profiles, while
+ chat responses are limited by 'PromptConfig::response_tokens' instead.
+ 'temperature' applies to both.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenAiConfig {
+    pub base_url: String,
+    // Best kept in 'OPENAI_API_KEY' rather than the file
+    pub api_key: Option<String>,
+    pub chat_model: String,
+    pub completion_model: String,
+    pub embedding_model: String,
+    pub max_tokens: usize,
+    pub temperature: f32,
+    pub max_retries: u32,
+    pub max_concurrent_requests: usize
+}
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        Self {
+            base_url: String::from(openai::DEFAULT_BASE_URL),
+            api_key: None,
+            chat_model: String::from("gpt-3.5-turbo"),
+            completion_model: String::from("gpt-3.5-turbo-instruct-0914"),
+            embedding_model: String::from("text-embedding-ada-002"),
+            max_tokens: 256,
+            temperature: 1.,
+            max_retries: openai::RetryPolicy::default().max_retries,
+            max_concurrent_requests: openai::DEFAULT_MAX_CONCURRENT_REQUESTS
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: String
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: String::from("0.0.0.0:3000")
+        }
+    }
+}
+/*
+ Where the character is kept.
+
+ 'json' keeps everything in one file at 'character_path', rewritten on
+ every save, with up to 'backups' copies kept in 'backups/' next to it,
+ one every 'backup_interval_secs'.
+ 'sqlite' keeps it in a database at 'database_path', only writing
+ whatever changed.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub character_path: PathBuf,
+    pub database_path: PathBuf,
+    pub backups: usize,
+    pub backup_interval_secs: u64
+}
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Json,
+            character_path: PathBuf::from("data/monikai.json"),
+            database_path: PathBuf::from("data/monikai.db"),
+            backups: 5,
+            backup_interval_secs: 60 * 60
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Json,
+    Sqlite
+}
+impl std::str::FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str( value: &str ) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(StorageBackend::Json),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(format!("Unknown storage backend '{}', expected 'json' or 'sqlite'", value))
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimingConfig {
+    // The longest a change goes unsaved
+    pub autosave_secs: u64,
+    // How long changes have to settle before they're saved
+    pub save_debounce_ms: u64,
+    pub conversation_timeout_secs: u64,
+    // Whether an open conversation is made into a memory when stopping, rather than kept for next time
+    pub end_conversation_on_shutdown: bool,
+    pub prune_interval_secs: u64
+}
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            autosave_secs: 5,
+            save_debounce_ms: 1000,
+            conversation_timeout_secs: 5 * 60,
+            end_conversation_on_shutdown: true,
+            prune_interval_secs: 15
+        }
+    }
+}
+/*
+ How memories are recalled while responding.
+
+ Up to 'top_k' memories at least 'min_similarity' alike to the key phrase
+ are recalled, and as many of those as fit in 'max_memory_chars' are
+ added to the prompt.
+
+ When 'mmr_lambda' is set, the memories are re-ranked with maximal
+ marginal relevance, where 1 favours relevance only and 0 diversity only.
+
+ With 'lexical_search', memories mentioning the key phrase's exact words
+ are ranked too, and both rankings are fused with reciprocal rank fusion.
+ A higher 'rrf_k' weighs the top few of each ranking less heavily. Rather
+ than 'min_similarity', memories found by their words need at least
+ 'min_term_match' of the key phrase's words, from 0 to 1.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetrievalConfig {
+    pub top_k: usize,
+    pub min_similarity: f64,
+    pub mmr_lambda: Option<f64>,
+    pub max_memory_chars: usize,
+    pub lexical_search: bool,
+    pub rrf_k: f64,
+    pub min_term_match: f64
+}
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self {
+            top_k: 3,
+            min_similarity: 0.75,
+            mmr_lambda: None,
+            max_memory_chars: 4000,
+            lexical_search: true,
+            rrf_k: 60.,
+            min_term_match: 0.5
+        }
+    }
+}
+/*
+ How the prompt is fit into the chat model's context.
+
+ 'context_tokens' is the model's context window, looked up by the chat
+ model's name unless set, of which 'response_tokens' are kept for the
+ response, the most the chat model is asked to give back.
+ Of what the description leaves, the user profile and recalled
+ memories get 'profile_share' and 'memory_share', and the conversation
+ the rest. Once the conversation outgrows its share, its oldest turns
+ are summarized by the completion model, a few at a time so each prompt
+ fits in 'completion_context_tokens', looked up the same way unless set.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptConfig {
+    pub context_tokens: Option<usize>,
+    pub response_tokens: usize,
+    pub profile_share: f64,
+    pub memory_share: f64,
+    pub completion_context_tokens: Option<usize>
+}
+impl Default for PromptConfig {
+    fn default() -> Self {
+        Self {
+            context_tokens: None,
+            response_tokens: 1024,
+            profile_share: 0.15,
+            memory_share: 0.25,
+            completion_context_tokens: None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, reason: String },
+    Parse { path: PathBuf, reason: String },
+    InvalidEnv { name: String, value: String }
+}
+impl fmt::Display for ConfigError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, reason } => write!(f, "Unable to read '{}': {}", path.display(), reason),
+            ConfigError::Parse { path, reason } => write!(f, "Unable to parse '{}': {}", path.display(), reason),
+            ConfigError::InvalidEnv { name, value } => write!(f, "Invalid value '{}' for '{}'", value, name)
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /*
+     Loads the file and environment layers.
+
+     A missing file is only an error if it was asked for explicitly,
+     otherwise the defaults are used.
+    */
+    pub fn load( path: Option<&Path> ) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default()
+        };
+
+        config.apply_env(|name| env::var(name).ok())?;
+
+        Ok(config)
+    }
+    pub fn from_file( path: &Path ) -> Result<Self, ConfigError> {
+        let config_string = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read { path: path.to_path_buf(), reason: err.to_string() })?;
+
+        toml::from_str(&config_string)
+            .map_err(|err| ConfigError::Parse { path: path.to_path_buf(), reason: err.to_string() })
+    }
+    /*
+     Overrides fields from environment variables, looked up through 'var'.
+
+     'OPENAI_BASE_URL', 'OPENAI_API_KEY', 'OPENAI_MAX_RETRIES' and
+     'OPENAI_MAX_CONCURRENT_REQUESTS' keep their usual names, everything
+     else is prefixed with 'MONIKAI_'.
+    */
+    pub fn apply_env( &mut self, var: impl Fn(&str) -> Option<String> ) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>( name: &str, value: String ) -> Result<T, ConfigError> {
+            value.trim().parse::<T>()
+                .map_err(|_| ConfigError::InvalidEnv { name: name.to_string(), value })
+        }
+
+        if let Some(value) = var("OPENAI_BASE_URL") { self.openai.base_url = value; }
+        if let Some(value) = var("OPENAI_API_KEY") { self.openai.api_key = Some(value); }
+        if let Some(value) = var("OPENAI_MAX_RETRIES") { self.openai.max_retries = parse("OPENAI_MAX_RETRIES", value)?; }
+        if let Some(value) = var("OPENAI_MAX_CONCURRENT_REQUESTS") { self.openai.max_concurrent_requests = parse("OPENAI_MAX_CONCURRENT_REQUESTS", value)?; }
+        if let Some(value) = var("MONIKAI_CHAT_MODEL") { self.openai.chat_model = value; }
+        if let Some(value) = var("MONIKAI_COMPLETION_MODEL") { self.openai.completion_model = value; }
+        if let Some(value) = var("MONIKAI_EMBEDDING_MODEL") { self.openai.embedding_model = value; }
+        if let Some(value) = var("MONIKAI_MAX_TOKENS") { self.openai.max_tokens = parse("MONIKAI_MAX_TOKENS", value)?; }
+        if let Some(value) = var("MONIKAI_TEMPERATURE") { self.openai.temperature = parse("MONIKAI_TEMPERATURE", value)?; }
+        if let Some(value) = var("MONIKAI_BIND") { self.server.bind = value; }
+        if let Some(value) = var("MONIKAI_STORAGE") { self.storage.backend = parse("MONIKAI_STORAGE", value)?; }
+        if let Some(value) = var("MONIKAI_CHARACTER") { self.storage.character_path = PathBuf::from(value); }
+        if let Some(value) = var("MONIKAI_DATABASE") { self.storage.database_path = PathBuf::from(value); }
+        if let Some(value) = var("MONIKAI_BACKUPS") { self.storage.backups = parse("MONIKAI_BACKUPS", value)?; }
+        if let Some(value) = var("MONIKAI_BACKUP_INTERVAL_SECS") { self.storage.backup_interval_secs = parse("MONIKAI_BACKUP_INTERVAL_SECS", value)?; }
+        if let Some(value) = var("MONIKAI_AUTOSAVE_SECS") { self.timing.autosave_secs = parse("MONIKAI_AUTOSAVE_SECS", value)?; }
+        if let Some(value) = var("MONIKAI_SAVE_DEBOUNCE_MS") { self.timing.save_debounce_ms = parse("MONIKAI_SAVE_DEBOUNCE_MS", value)?; }
+        if let Some(value) = var("MONIKAI_CONVERSATION_TIMEOUT_SECS") { self.timing.conversation_timeout_secs = parse("MONIKAI_CONVERSATION_TIMEOUT_SECS", value)?; }
+        if let Some(value) = var("MONIKAI_END_CONVERSATION_ON_SHUTDOWN") { self.timing.end_conversation_on_shutdown = parse("MONIKAI_END_CONVERSATION_ON_SHUTDOWN", value)?; }
+        if let Some(value) = var("MONIKAI_PRUNE_INTERVAL_SECS") { self.timing.prune_interval_secs = parse("MONIKAI_PRUNE_INTERVAL_SECS", value)?; }
+        if let Some(value) = var("MONIKAI_TOP_K") { self.retrieval.top_k = parse("MONIKAI_TOP_K", value)?; }
+        if let Some(value) = var("MONIKAI_MIN_SIMILARITY") { self.retrieval.min_similarity = parse("MONIKAI_MIN_SIMILARITY", value)?; }
+        if let Some(value) = var("MONIKAI_MMR_LAMBDA") { self.retrieval.mmr_lambda = Some(parse("MONIKAI_MMR_LAMBDA", value)?); }
+        if let Some(value) = var("MONIKAI_MAX_MEMORY_CHARS") { self.retrieval.max_memory_chars = parse("MONIKAI_MAX_MEMORY_CHARS", value)?; }
+        if let Some(value) = var("MONIKAI_LEXICAL_SEARCH") { self.retrieval.lexical_search = parse("MONIKAI_LEXICAL_SEARCH", value)?; }
+        if let Some(value) = var("MONIKAI_RRF_K") { self.retrieval.rrf_k = parse("MONIKAI_RRF_K", value)?; }
+        if let Some(value) = var("MONIKAI_MIN_TERM_MATCH") { self.retrieval.min_term_match = parse("MONIKAI_MIN_TERM_MATCH", value)?; }
+        if let Some(value) = var("MONIKAI_CONTEXT_TOKENS") { self.prompt.context_tokens = Some(parse("MONIKAI_CONTEXT_TOKENS", value)?); }
+        if let Some(value) = var("MONIKAI_RESPONSE_TOKENS") { self.prompt.response_tokens = parse("MONIKAI_RESPONSE_TOKENS", value)?; }
+        if let Some(value) = var("MONIKAI_COMPLETION_CONTEXT_TOKENS") { self.prompt.completion_context_tokens = Some(parse("MONIKAI_COMPLETION_CONTEXT_TOKENS", value)?); }
+
+        Ok(())
+    }
+}
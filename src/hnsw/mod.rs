@@ -0,0 +1,283 @@
+use std::cmp::{ Ordering, Reverse };
+use std::collections::{ BinaryHeap, HashSet };
+
+use crate::{ Serialize, Deserialize };
+use crate::linalg;
+
+// Neighbors kept per node on each layer, and twice that on the bottom one
+const M: usize = 16;
+const M_MAX_0: usize = 2 * M;
+// How many candidates are weighed when linking a new node
+const EF_CONSTRUCTION: usize = 100;
+
+/*
+ A hierarchical navigable small world graph, for finding the nearest
+ of many unit vectors without comparing against every one of them.
+
+ Only the links are kept here - the vectors are passed in, indexed by
+ node id, so they can live wherever they already do.
+ Removed nodes are unlinked and left as holes until 'renumber' is used.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hnsw {
+    nodes: Vec<Option<Node>>,
+    entry_point: Option<usize>,
+    rng_state: u64
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    // One list per layer, from the bottom up
+    neighbors: Vec<Vec<usize>>
+}
+impl Default for Hnsw {
+    fn default() -> Self {
+        Self {
+            nodes: Vec::new(),
+            entry_point: None,
+            rng_state: 0x9E3779B97F4A7C15
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    distance: f64,
+    node: usize
+}
+impl PartialEq for Candidate {
+    fn eq( &self, other: &Self ) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Candidate {}
+impl PartialOrd for Candidate {
+    fn partial_cmp( &self, other: &Self ) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Candidate {
+    fn cmp( &self, other: &Self ) -> Ordering {
+        self.distance.total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+fn distance( a: &[f32], b: &[f32] ) -> f64 {
+    1f64 - linalg::dot_product_f32( a, b ).unwrap_or(0f32) as f64
+}
+// Keeps only the 'max' neighbors closest to 'vector'.
+fn shrink( neighbors: &mut Vec<usize>, vector: &[f32], max: usize, vectors: &[Vec<f32>] ) {
+    if neighbors.len() <= max {
+        return;
+    }
+
+    neighbors.sort_by(|a, b| distance(vector, &vectors[*a]).total_cmp(&distance(vector, &vectors[*b])));
+    neighbors.truncate(max);
+}
+fn max_neighbors( layer: usize ) -> usize {
+    if layer == 0 { M_MAX_0 } else { M }
+}
+
+impl Hnsw {
+    pub fn contains( &self, node: usize ) -> bool {
+        matches!(self.nodes.get(node), Some(Some(_)))
+    }
+    fn level( &self, node: usize ) -> usize {
+        self.nodes[node].as_ref()
+            .map(|node| node.neighbors.len() - 1)
+            .unwrap_or(0)
+    }
+    fn neighbors( &self, node: usize, layer: usize ) -> &[usize] {
+        self.nodes[node].as_ref()
+            .and_then(|node| node.neighbors.get(layer))
+            .map(|neighbors| neighbors.as_slice())
+            .unwrap_or(&[])
+    }
+    // Levels fall off exponentially, so each layer holds about 1/M of the one below.
+    fn random_level( &mut self ) -> usize {
+        // xorshift64*
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let random = self.rng_state.wrapping_mul(0x2545F4914F6CDD1D);
+
+        let uniform = ((random >> 11) as f64 + 1f64) / (1u64 << 53) as f64;
+
+        (-uniform.ln() / (M as f64).ln()) as usize
+    }
+    // The 'ef' nodes closest to 'query' on one layer, found by walking out from 'entry_points'.
+    fn search_layer( &self, query: &[f32], entry_points: &[usize], ef: usize, layer: usize, vectors: &[Vec<f32>] ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        let mut found: BinaryHeap<Candidate> = BinaryHeap::new();
+
+        for &node in entry_points {
+            let candidate = Candidate { distance: distance(query, &vectors[node]), node };
+
+            candidates.push(Reverse(candidate));
+            found.push(candidate);
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if found.peek().is_some_and(|furthest| closest.distance > furthest.distance) {
+                break;
+            }
+
+            for &neighbor in self.neighbors(closest.node, layer) {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+
+                let candidate = Candidate { distance: distance(query, &vectors[neighbor]), node: neighbor };
+                if found.len() < ef || found.peek().is_some_and(|furthest| candidate.distance < furthest.distance) {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+    // Walks greedily down to 'layer', returning where to carry on searching from.
+    fn descend( &self, query: &[f32], layer: usize, vectors: &[Vec<f32>] ) -> Vec<usize> {
+        let Some(entry_point) = self.entry_point else {
+            return Vec::new();
+        };
+
+        let mut entry_points = vec![entry_point];
+        for upper_layer in (layer + 1..=self.level(entry_point)).rev() {
+            entry_points = vec![self.search_layer(query, &entry_points, 1, upper_layer, vectors)[0].node];
+        }
+
+        entry_points
+    }
+    /*
+     Links 'node' into the graph.
+
+     Its vector must already be in 'vectors', alongside every other node's.
+    */
+    pub fn insert( &mut self, node: usize, vectors: &[Vec<f32>] ) {
+        if self.nodes.len() <= node {
+            self.nodes.resize(node + 1, None);
+        }
+
+        let level = self.random_level();
+        self.nodes[node] = Some(Node { neighbors: vec![Vec::new(); level + 1] });
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+        let top_level = self.level(entry_point);
+
+        let query = &vectors[node];
+        let mut entry_points = self.descend(query, level, vectors);
+        for layer in (0..=level.min(top_level)).rev() {
+            let found = self.search_layer(query, &entry_points, EF_CONSTRUCTION, layer, vectors);
+
+            let chosen: Vec<usize> = found.iter()
+                .map(|candidate| candidate.node)
+                .filter(|candidate| *candidate != node)
+                .take(M)
+                .collect();
+            for &neighbor in &chosen {
+                if let Some(neighbor_node) = self.nodes[neighbor].as_mut() {
+                    neighbor_node.neighbors[layer].push(node);
+                    shrink(&mut neighbor_node.neighbors[layer], &vectors[neighbor], max_neighbors(layer), vectors);
+                }
+            }
+            if let Some(new_node) = self.nodes[node].as_mut() {
+                new_node.neighbors[layer] = chosen;
+            }
+
+            entry_points = found.iter().map(|candidate| candidate.node).collect();
+        }
+
+        if level > top_level {
+            self.entry_point = Some(node);
+        }
+    }
+    /*
+     Unlinks 'node' from the graph.
+
+     Anything that linked to it is relinked to its neighbors instead,
+     so the graph stays navigable around the hole.
+    */
+    pub fn remove( &mut self, node: usize, vectors: &[Vec<f32>] ) {
+        let Some(removed) = self.nodes.get_mut(node).and_then(|removed| removed.take()) else {
+            return;
+        };
+
+        for (layer, removed_neighbors) in removed.neighbors.iter().enumerate() {
+            for (other, other_node) in self.nodes.iter_mut().enumerate() {
+                let Some(other_neighbors) = other_node.as_mut().and_then(|other_node| other_node.neighbors.get_mut(layer)) else {
+                    continue;
+                };
+                let Some(position) = other_neighbors.iter().position(|neighbor| *neighbor == node) else {
+                    continue;
+                };
+
+                other_neighbors.remove(position);
+                for &replacement in removed_neighbors {
+                    if replacement != other && !other_neighbors.contains(&replacement) {
+                        other_neighbors.push(replacement);
+                    }
+                }
+                shrink(other_neighbors, &vectors[other], max_neighbors(layer), vectors);
+            }
+        }
+
+        if self.entry_point == Some(node) {
+            self.entry_point = self.nodes.iter()
+                .enumerate()
+                .filter_map(|(id, other)| other.as_ref().map(|other| (id, other.neighbors.len())))
+                .max_by_key(|(id, levels)| (*levels, Reverse(*id)))
+                .map(|(id, _)| id);
+        }
+    }
+    // Moves every node to 'mapping[node]', dropping those mapped to None.
+    pub fn renumber( &mut self, mapping: &[Option<usize>] ) {
+        let length = mapping.iter().flatten().map(|node| node + 1).max().unwrap_or(0);
+        let mut nodes: Vec<Option<Node>> = vec![None; length];
+
+        for (old, node) in self.nodes.drain(..).enumerate() {
+            let (Some(mut node), Some(Some(new))) = (node, mapping.get(old)) else {
+                continue;
+            };
+
+            for neighbors in node.neighbors.iter_mut() {
+                *neighbors = neighbors.iter()
+                    .filter_map(|neighbor| mapping.get(*neighbor).copied().flatten())
+                    .collect();
+            }
+            nodes[*new] = Some(node);
+        }
+
+        self.nodes = nodes;
+        self.entry_point = self.entry_point.and_then(|entry_point| mapping.get(entry_point).copied().flatten());
+    }
+    /*
+     The (up to) 'k' nodes closest to 'query', as (node, similarity) pairs.
+
+     'ef' trades speed for accuracy, and is raised to 'k' if lower.
+    */
+    pub fn search( &self, query: &[f32], k: usize, ef: usize, vectors: &[Vec<f32>] ) -> Vec<(usize, f64)> {
+        let entry_points = self.descend(query, 0, vectors);
+        if entry_points.is_empty() {
+            return Vec::new();
+        }
+
+        self.search_layer(query, &entry_points, ef.max(k), 0, vectors)
+            .into_iter()
+            .take(k)
+            .map(|candidate| (candidate.node, 1f64 - candidate.distance))
+            .collect()
+    }
+}
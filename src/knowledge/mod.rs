@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::{ Serialize, Deserialize };
+use crate::bm25;
+use crate::memory::{ Memory, MemoryId };
+
+pub type FactId = ulid::Ulid;
+
+/*
+ Facts about the MC and the people and things in their life, as
+ subject, predicate and object, such as "MC's sister" "is named" "Sarah".
+
+ Unlike the profile, which is prose for the model to read, these are
+ looked up by their words, so what's asked about is answered with exactly
+ what was learned. Each fact notes every memory it was learned from,
+ except those set by hand, which rest on no memory and are kept until
+ they're removed.
+*/
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Knowledge {
+    pub facts: Vec<Fact>
+}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Fact {
+    pub id: FactId,
+    pub subject: String,
+    pub predicate: String,
+    pub object: String,
+    // From 0 to 1, how sure the model was, or 1 if set by hand
+    pub confidence: f64,
+    // Empty for facts set by hand
+    pub sources: Vec<MemoryId>,
+    // When it was last learned or corrected, in seconds since the Unix epoch
+    pub timestamp: u64
+}
+impl fmt::Display for Fact {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(f, "{} {} {}", self.subject, self.predicate, self.object)
+    }
+}
+impl Fact {
+    // Whether the two say the same thing, whatever the case.
+    fn same_as( &self, other: &Fact ) -> bool {
+        self.subject.eq_ignore_ascii_case(&other.subject)
+            && self.predicate.eq_ignore_ascii_case(&other.predicate)
+            && self.object.eq_ignore_ascii_case(&other.object)
+    }
+    // Whether it was set by hand, rather than learned from memories.
+    pub fn set_by_hand( &self ) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+// A fact as the model gives it back when extracting
+#[derive(Debug, Deserialize)]
+struct ExtractedFact {
+    subject: String,
+    predicate: String,
+    object: String,
+    #[serde(default = "ExtractedFact::default_confidence")]
+    confidence: f64
+}
+impl ExtractedFact {
+    fn default_confidence() -> f64 {
+        0.5
+    }
+}
+
+// The words worth matching on, leaving out single letters like the 's' of "sister's"
+fn terms( text: &str ) -> HashSet<String> {
+    bm25::tokenize(text)
+        .filter(|term| term.chars().count() > 1)
+        .collect()
+}
+
+impl Knowledge {
+    // The prompt asking the model for the facts a memory's conversation states.
+    pub fn extraction_prompt( memory: &Memory ) -> String {
+        format!("
+            In the following conversation, you are Monikai.
+            List the FACTS the conversation states about the MC and the people, places and things in their life.
+
+            Give each as a subject, a short predicate and an object, calling the MC 'MC', and with how confident you are from 0 to 1.
+            Leave out opinions, guesses and anything only said in passing. Respond with a JSON list. For example:
+            [{{\"subject\": \"MC's sister\", \"predicate\": \"is named\", \"object\": \"Sarah\", \"confidence\": 0.9}},
+             {{\"subject\": \"MC\", \"predicate\": \"lives in\", \"object\": \"Chicago\", \"confidence\": 0.7}}]
+
+            CONVERSATION:
+            {}
+
+            FACTS:
+
+        ", memory.conversation)
+    }
+    /*
+     What's known with the model's answer to 'extraction_prompt' added,
+     or None if the answer can't be read.
+
+     Facts already known take the memory as another source, keeping
+     the higher confidence, rather than being added again. Facts set by
+     hand are left as they are.
+    */
+    pub fn learned( &self, answer: &str, memory: &Memory ) -> Option<Self> {
+        let start = answer.find('[')?;
+        let end = answer.rfind(']')?;
+        let extracted_facts: Vec<ExtractedFact> = serde_json::from_str(answer.get(start..=end)?).ok()?;
+
+        let mut facts = self.facts.clone();
+        for extracted_fact in extracted_facts {
+            let fact = Fact {
+                id: FactId::new(),
+                subject: extracted_fact.subject.trim().to_string(),
+                predicate: extracted_fact.predicate.trim().to_string(),
+                object: extracted_fact.object.trim().to_string(),
+                confidence: extracted_fact.confidence.clamp(0., 1.),
+                sources: vec!(memory.id),
+                timestamp: memory.timestamp
+            };
+            if fact.subject.is_empty() || fact.predicate.is_empty() || fact.object.is_empty() {
+                continue;
+            }
+
+            match facts.iter_mut().find(|known| known.same_as(&fact)) {
+                Some(known) if known.set_by_hand() => {},
+                Some(known) => {
+                    known.confidence = known.confidence.max(fact.confidence);
+                    if !known.sources.contains(&memory.id) {
+                        known.sources.push(memory.id);
+                    }
+                    known.timestamp = known.timestamp.max(fact.timestamp);
+                },
+                None => facts.push(fact)
+            }
+        }
+
+        Some(Self { facts })
+    }
+    pub fn fact( &self, id: FactId ) -> Option<&Fact> {
+        self.facts.iter().find(|fact| fact.id == id)
+    }
+    // The facts mentioning some text in any part, or every fact for no text, oldest first.
+    pub fn about( &self, text: &str ) -> Vec<&Fact> {
+        let text = text.trim().to_lowercase();
+        let mut facts: Vec<&Fact> = self.facts.iter()
+            .filter(|fact| fact.to_string().to_lowercase().contains(&text))
+            .collect();
+        facts.sort_by_key(|fact| fact.timestamp);
+
+        facts
+    }
+    /*
+     The facts sharing the most words with a message, best first.
+
+     Ties go to the more confident fact, then the newer, so where two
+     facts disagree, the same one is always given first.
+    */
+    pub fn relevant( &self, message: &str ) -> Vec<&Fact> {
+        let message_terms = terms(message);
+
+        let mut scored: Vec<(usize, &Fact)> = self.facts.iter()
+            .map(|fact| (terms(&fact.to_string()).intersection(&message_terms).count(), fact))
+            .filter(|(shared, _)| *shared > 0)
+            .collect();
+        scored.sort_by(|(a_shared, a), (b_shared, b)| b_shared.cmp(a_shared)
+            .then(b.confidence.total_cmp(&a.confidence))
+            .then(b.timestamp.cmp(&a.timestamp)));
+
+        scored.into_iter()
+            .map(|(_, fact)| fact)
+            .collect()
+    }
+    /*
+     Replaces what a fact says, returning it, now set by hand and as
+     certain as can be.
+
+     Any other fact saying the same thing is removed, leaving just this one.
+    */
+    pub fn edit( &mut self, id: FactId, subject: &str, predicate: &str, object: &str, timestamp: u64 ) -> Option<&Fact> {
+        let fact = self.facts.iter_mut().find(|fact| fact.id == id)?;
+
+        fact.subject = subject.trim().to_string();
+        fact.predicate = predicate.trim().to_string();
+        fact.object = object.trim().to_string();
+        fact.confidence = 1.;
+        fact.sources.clear();
+        fact.timestamp = timestamp;
+
+        let edited = fact.clone();
+        self.facts.retain(|fact| fact.id == id || !fact.same_as(&edited));
+
+        self.fact(id)
+    }
+    pub fn remove( &mut self, id: FactId ) -> Option<Fact> {
+        let position = self.facts.iter().position(|fact| fact.id == id)?;
+
+        Some(self.facts.remove(position))
+    }
+    /*
+     Drops a memory from the facts' sources, removing the facts
+     learned only from it. Facts set by hand never had a source to
+     lose, so are kept.
+    */
+    pub fn forget( &mut self, id: MemoryId ) {
+        self.facts.retain_mut(|fact| {
+            if fact.set_by_hand() {
+                return true;
+            }
+            fact.sources.retain(|source| *source != id);
+
+            !fact.sources.is_empty()
+        });
+    }
+}
@@ -1,36 +1,92 @@
-pub fn magnitude( input: &Vec<f64> ) -> f64 {
-    let elements_squared = input
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinalgError {
+    // Usually means the vectors came from different embedding models
+    DimensionMismatch { left: usize, right: usize }
+}
+impl fmt::Display for LinalgError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            LinalgError::DimensionMismatch { left, right } => write!(f, "Incompatible vectors of length {} and {}", left, right)
+        }
+    }
+}
+impl std::error::Error for LinalgError {}
+
+pub fn magnitude( input: &[f64] ) -> f64 {
+    input
         .iter()
         .map(|element| element.powi(2))
-        .collect::<Vec<f64>>();
-    
-    let elements_sum = elements_squared
-        .iter()
-        .sum::<f64>();
-    
-    let sqrt_element_sum = elements_sum.sqrt();
-    
-    sqrt_element_sum
+        .sum::<f64>()
+        .sqrt()
 }
 
-pub fn dot_product( input_1: &Vec<f64>, input_2: &Vec<f64> ) -> f64 {
+#[cfg(test)]
+pub fn dot_product( input_1: &[f64], input_2: &[f64] ) -> Result<f64, LinalgError> {
     if input_1.len() != input_2.len() {
-        panic!("Incompatible vectors!");
+        return Err(LinalgError::DimensionMismatch { left: input_1.len(), right: input_2.len() });
     }
 
-    let like_indices_mulitiplied = input_1
+    Ok(input_1
         .iter()
-        .enumerate()
-        .map(|(element_index, element)| element * input_2[element_index] )
-        .collect::<Vec<f64>>();
+        .zip(input_2.iter())
+        .map(|(element_1, element_2)| element_1 * element_2 )
+        .sum::<f64>())
+}
 
-    let sum_all = like_indices_mulitiplied
+// For embeddings, which are kept as f32.
+pub fn dot_product_f32( input_1: &[f32], input_2: &[f32] ) -> Result<f32, LinalgError> {
+    if input_1.len() != input_2.len() {
+        return Err(LinalgError::DimensionMismatch { left: input_1.len(), right: input_2.len() });
+    }
+
+    Ok(input_1
         .iter()
-        .sum::<f64>();
+        .zip(input_2.iter())
+        .map(|(element_1, element_2)| element_1 * element_2 )
+        .sum::<f32>())
+}
 
-    sum_all
+/*
+ Cosine similarity, between -1 (opposite) and 1 (identical direction).
+
+ A zero vector has no direction, so it is considered unrelated (0) to
+ everything, as are vectors containing NaN or infinite elements.
+ Vectors of different lengths can't be compared at all.
+
+ Memory recall normalizes embeddings up front instead (see 'normalize'),
+ so this is only kept to check that against.
+*/
+#[cfg(test)]
+pub fn cosine_similarity( input_1: &[f64], input_2: &[f64] ) -> Result<f64, LinalgError> {
+    let dot_product = dot_product( input_1, input_2 )?;
+    let magnitudes = magnitude( input_1 ) * magnitude( input_2 );
+
+    let similarity = dot_product / magnitudes;
+    if magnitudes == 0f64 || !similarity.is_finite() {
+        return Ok(0f64);
+    }
+
+    // Rounding can push parallel vectors just past 1
+    Ok(similarity.clamp(-1f64, 1f64))
 }
+/*
+ Scales a vector to a magnitude of 1, so the cosine similarity of
+ two normalized vectors is just their dot product.
 
-pub fn cosine_similarity( input_1: &Vec<f64>, input_2: &Vec<f64> ) -> f64 {
-    dot_product( input_1, input_2 ) / ( magnitude( input_1 ) + magnitude( input_2 ) )
-}
\ No newline at end of file
+ Vectors without a direction (zero, NaN or infinite) become zero vectors,
+ keeping them unrelated to everything.
+*/
+pub fn normalize( input: &[f64] ) -> Vec<f64> {
+    let magnitude = magnitude( input );
+
+    if magnitude == 0f64 || !magnitude.is_finite() {
+        return vec![0f64; input.len()];
+    }
+
+    input
+        .iter()
+        .map(|element| element / magnitude)
+        .collect()
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+
+use crate::{ Serialize, Deserialize };
+use crate::openai;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: String
+}
+impl fmt::Display for Message {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(f, "{}: {}", self.role, self.content)
+    }
+}
+
+/*
+ Everything that can go wrong while prompting a provider.
+
+ None of these are fatal; the REPL and web client report them
+ and carry on with the conversation.
+*/
+#[derive(Debug)]
+pub enum Error {
+    MissingApiKey,
+    RateLimited { retry_after: Option<Duration> },
+    Http { status: u16, body: String },
+    Transport(String),
+    MalformedResponse(String),
+    EmptyChoices
+}
+impl fmt::Display for Error {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            Error::MissingApiKey => write!(f, "No API key was set, try setting 'OPENAI_API_KEY'"),
+            Error::RateLimited { retry_after: Some(retry_after) } => write!(f, "Rate limited, retry after {} second/s", retry_after.as_secs()),
+            Error::RateLimited { retry_after: None } => write!(f, "Rate limited"),
+            Error::Http { status, body } => write!(f, "Request failed with status {}: {}", status, body),
+            Error::Transport(reason) => write!(f, "Unable to reach the provider: {}", reason),
+            Error::MalformedResponse(reason) => write!(f, "Malformed response: {}", reason),
+            Error::EmptyChoices => write!(f, "The provider returned no choices")
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/*
+ The three kinds of model a Monikai talks to.
+
+ Anything which can answer a chat, finish a prompt, or embed a string
+ can be swapped in for the OpenAI defaults, be it Ollama, a llama.cpp
+ server, or an in-process mock.
+*/
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn chat( &self, messages: Vec<Message> ) -> Result<Message, Error>;
+    // Which model chats, to count tokens and find its context window by.
+    fn model( &self ) -> &str;
+
+    /*
+     Sends each piece of the response down 'deltas' as it is generated,
+     then returns the whole message.
+
+     Providers which can't stream send the whole response as one piece.
+    */
+    async fn chat_stream( &self, messages: Vec<Message>, deltas: mpsc::UnboundedSender<String> ) -> Result<Message, Error> {
+        let message = self.chat(messages).await?;
+        deltas.send(message.content.clone()).ok();
+
+        Ok(message)
+    }
+}
+#[async_trait]
+pub trait CompletionProvider: Send + Sync {
+    async fn complete( &self, prompt: String ) -> Result<String, Error>;
+    // Which model completes, to count tokens and find its context window by.
+    fn model( &self ) -> &str;
+    // How many tokens each completion may take, which the prompt has to leave room for.
+    fn max_tokens( &self ) -> usize;
+}
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    async fn embed( &self, input: &str ) -> Result<Vec<f64>, Error>;
+    // Which model embeds, since embeddings from different models can't be compared.
+    fn model( &self ) -> &str;
+}
+
+/*
+ The set of providers a Monikai prompts through.
+
+ Each role is held separately, so a local model can generate chat
+ while OpenAI still handles embeddings (or any other mix).
+*/
+#[derive(Clone)]
+pub struct Providers {
+    pub chat: Arc<dyn ChatProvider>,
+    pub completion: Arc<dyn CompletionProvider>,
+    pub embedding: Arc<dyn EmbeddingProvider>
+}
+impl Providers {
+    // Uses one provider for every role.
+    pub fn from_single<P>( provider: P ) -> Self
+    where
+        P: ChatProvider + CompletionProvider + EmbeddingProvider + 'static
+    {
+        let provider = Arc::new(provider);
+
+        Self {
+            chat: provider.clone(),
+            completion: provider.clone(),
+            embedding: provider
+        }
+    }
+}
+impl Default for Providers {
+    fn default() -> Self {
+        Self::from_single(openai::OpenAi::default())
+    }
+}
+impl fmt::Debug for Providers {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        f.debug_struct("Providers").finish_non_exhaustive()
+    }
+}
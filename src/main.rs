@@ -1,15 +1,23 @@
 #[cfg(test)]
 mod tests;
+mod cli;
+mod config;
+mod llm;
 mod openai;
 mod memory;
+mod hnsw;
+mod bm25;
+mod prompt;
+mod profile;
+mod knowledge;
 mod monikai;
 mod linalg;
 mod print;
+mod storage;
 
 use std::{
-    fs::{ OpenOptions, File },
     env,
-    io::Read,
+    path::Path,
     sync::{ Arc }
 };
 use tokio::sync::Mutex;
@@ -18,34 +26,212 @@ use serde::{ Serialize, Deserialize };
 
 #[tokio::main]
 async fn main() {
+    // Build the config from the file, environment and flags
+    let cli = <cli::Cli as clap::Parser>::parse();
+    let mut config = match config::Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            print::error(&err.to_string());
+            std::process::exit(1);
+        }
+    };
+    cli.apply(&mut config);
+
+    let mut storage = match storage::open(&config.storage) {
+        Ok(storage) => storage,
+        Err(err) => {
+            print::error(&err.to_string());
+            std::process::exit(1);
+        }
+    };
+
+    // Older characters are brought up to date before anything reads them
+    let dry_run = matches!(cli.command, Some(cli::Command::Migrate { dry_run: true }));
+    match storage.migrate(dry_run) {
+        Ok(migrations) => {
+            for migration in &migrations {
+                print::info(&format!("{}: {}", if dry_run { "Would migrate" } else { "Migrated" }, migration));
+            }
+            if migrations.is_empty() && matches!(cli.command, Some(cli::Command::Migrate { .. })) {
+                print::info(&format!("'{}' is already up to date", storage.location()));
+            }
+        },
+        // Left for loading, which can fall back to a backup
+        Err(storage::StorageError::Parse(_)) => {},
+        Err(err) => {
+            print::error(&err.to_string());
+            std::process::exit(1);
+        }
+    }
+
+    // One-off maintenance commands, which only touch storage
+    if let Some(command) = &cli.command {
+        if let Some(result) = maintain(command, storage.as_mut()) {
+            if let Err(err) = result {
+                print::error(&err.to_string());
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
+
     print::info("Initializing Monikai");
-    // Open the data file
-    let mut character_file_handle: File = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("data/monikai.json")
-        .expect("Unable to get handle on './data/monikai.json'!");
-
-    // Read from said data file
-    let mut character_json_string = String::new();
-    character_file_handle.read_to_string(&mut character_json_string)
-        .expect("Unable to read './data/monikai.json'!");
-
-    // Close the file handle
-    drop(character_file_handle);
+    let mut character = match load_character(storage.as_mut()) {
+        Ok(character) => character,
+        Err(err) => {
+            print::error(&err.to_string());
+            std::process::exit(1);
+        }
+    };
     print::info("Done!");
 
     // Build a thread and asynchronus reference to the character
-    let monikai = Arc::new(
-        Mutex::new(
-            serde_json::from_str::<monikai::Monikai>(&character_json_string)
-                .expect("Unable to parse!")
-        ));
-
-    // Start the repl and frontend
-    tokio::spawn(monikai::monikai_memory_agent( monikai.clone() ));
-    tokio::spawn(monikai::monikai_backend( monikai.clone() ));
-    tokio::spawn(monikai::monikai_repl( monikai.clone() ));
-
-    monikai::monikai_autosave( monikai ).await;
-}
\ No newline at end of file
+    character.providers = llm::Providers::from_single(openai::OpenAi::from_config(&config.openai)
+        .with_response_tokens(config.prompt.response_tokens));
+    character.retrieval = config.retrieval.clone();
+    character.prompt = config.prompt.clone();
+    character.index.sync(&character.memories);
+
+    let monikai = Arc::new(Mutex::new(character));
+    let (storage_handle, storage_requests) = storage::StorageHandle::new();
+
+    // Only the autosave task writes to storage, everything else asks it to
+    let autosave = tokio::spawn(monikai::monikai_autosave( monikai.clone(), storage, config.timing.clone(), storage_requests ));
+
+    // Start whichever of the repl and frontend were asked for, until they're done or told to stop
+    let shutdown = monikai::Shutdown::default();
+    tokio::spawn(monikai::monikai_memory_agent( monikai.clone(), config.timing.clone() ));
+    let backend = || tokio::spawn(monikai::monikai_backend( monikai.clone(), config.server.clone(), shutdown.clone() ));
+    // Whether the backend stopped on its own, which only happens when it fails
+    let mut failed = false;
+    match cli.command {
+        Some(cli::Command::Serve) => {
+            print::info(&format!("Serving on '{}'", config.server.bind));
+
+            // Without a REPL, the backend is the whole point, so stop if it does
+            tokio::select! {
+                result = backend() => failed = backend_failed(result, &config.server.bind),
+                _ = monikai::shutdown_signal() => {}
+            }
+        },
+        Some(cli::Command::Chat) => {
+            // Once stdin closes, there's nothing left to do
+            tokio::select! {
+                _ = monikai::monikai_repl( monikai.clone(), storage_handle.clone(), shutdown.clone() ) => {},
+                _ = monikai::shutdown_signal() => {}
+            }
+        },
+        _ => {
+            tokio::spawn(monikai::monikai_repl( monikai.clone(), storage_handle.clone(), shutdown.clone() ));
+
+            tokio::select! {
+                result = backend() => failed = backend_failed(result, &config.server.bind),
+                _ = monikai::shutdown_signal() => {}
+            }
+        }
+    }
+
+    shutdown.trigger();
+    monikai::monikai_shutdown( monikai, storage_handle, config.timing.end_conversation_on_shutdown ).await;
+    autosave.await.ok();
+
+    // Reading stdin blocks a thread which would otherwise keep the runtime from stopping,
+    // and a service manager needs to know if the backend failed, to restart it
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+// Reports why the backend stopped, returning whether it failed.
+fn backend_failed( result: Result<std::io::Result<()>, tokio::task::JoinError>, bind: &str ) -> bool {
+    match result {
+        Ok(Ok(())) => false,
+        Ok(Err(err)) => {
+            print::error(&format!("Unable to serve on '{}': {}", bind, err));
+            true
+        },
+        Err(err) => {
+            print::error(&format!("The backend stopped unexpectedly: {}", err));
+            true
+        }
+    }
+}
+
+/*
+ Runs a one-off maintenance command, or returns None for
+ the commands which start the Monikai instead.
+*/
+fn maintain( command: &cli::Command, storage: &mut dyn storage::Storage ) -> Option<Result<(), storage::StorageError>> {
+    let result = match command {
+        cli::Command::Migrate { .. } => Ok(()),
+        cli::Command::Inspect => load_character(storage).map(|monikai| {
+            println!("{}", serde_json::to_string_pretty(&monikai.without_embeddings()).unwrap());
+        }),
+        cli::Command::Export { path } => load_character(storage).and_then(|monikai| {
+            let monikai_json_string = serde_json::to_string_pretty(&monikai).unwrap();
+
+            if path == Path::new("-") {
+                println!("{}", monikai_json_string);
+            } else {
+                std::fs::write(path, monikai_json_string)
+                    .map_err(|err| storage::StorageError::Io(format!("Unable to write '{}': {}", path.display(), err)))?;
+                print::info(&format!("Exported to '{}'", path.display()));
+            }
+            Ok(())
+        }),
+        cli::Command::Search { text, from, to } => {
+            let from = from.unwrap_or(0);
+            let to = to.unwrap_or(u64::MAX);
+            let memories = match text {
+                Some(text) => storage.search_memories(text),
+                None => storage.memories_between(from, to)
+            };
+
+            memories.map(|memories| {
+                let memories: Vec<memory::Memory> = memories.into_iter()
+                    .filter(|memory| (from..=to).contains(&memory.timestamp))
+                    .map(|memory| memory.without_embedding())
+                    .collect();
+
+                println!("{}", serde_json::to_string_pretty(&memories).unwrap());
+            })
+        },
+        cli::Command::Import { path, merge } => load_character(&mut storage::JsonStorage::new(path)).and_then(|imported| {
+            let monikai = if *merge {
+                let mut monikai = load_character(storage)?;
+                let merged_count = monikai.merge_memories(imported.memories);
+
+                print::info(&format!("Merged {} new memory/s", merged_count));
+                monikai
+            } else {
+                imported
+            };
+
+            storage.save(&monikai)?;
+            print::info(&format!("Imported '{}' into '{}'", path.display(), storage.location()));
+            Ok(())
+        }),
+        cli::Command::Serve | cli::Command::Chat => return None
+    };
+
+    Some(result)
+}
+
+/*
+ Loads the character.
+
+ If it's there but can't be parsed, such as after a crash,
+ the newest backup that can be is used instead.
+*/
+fn load_character( storage: &mut dyn storage::Storage ) -> Result<monikai::Monikai, storage::StorageError> {
+    match storage.load() {
+        Err(err @ storage::StorageError::Parse(_)) => {
+            let Some((monikai, backup)) = storage.recover() else {
+                return Err(err);
+            };
+            print::error(&err.to_string());
+            print::info(&format!("Recovered the character from '{}'", backup));
+
+            Ok(monikai)
+        },
+        result => result
+    }
+}
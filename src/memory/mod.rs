@@ -1,22 +1,135 @@
 use crate::{Serialize, Deserialize};
-use crate::openai;
+use crate::{ llm, linalg, hnsw, bm25 };
+use crate::config::RetrievalConfig;
+use std::collections::HashSet;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use base64::{ Engine, engine::general_purpose::STANDARD as BASE64 };
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
 pub struct Memory {
+    pub id: MemoryId,
+    // Of the whole conversation, or the mean of the chunks' if it has any
     pub embedding: Embedding,
+    pub chunks: Vec<Chunk>,
+    // The model that made the embedding
+    pub embedding_model: String,
     pub user_profile: UserProfile,
     pub interaction_summary: InteractionSummary,
     pub conversation: Conversation,
+    // Where the conversation was had, and who by (by message role)
+    pub channels: Vec<Channel>,
+    pub participants: Vec<String>,
+    pub tags: Vec<String>,
     pub timestamp: u64,
-    pub times_read: usize
+    pub times_read: usize,
+    // When it was last recalled, in seconds since the Unix epoch
+    pub last_accessed: Option<u64>,
+    // When it was recalled each time, oldest first
+    pub recalls: Vec<u64>
 }
-pub type Embedding = Vec<f64>;
+/*
+ Names a memory for as long as it's kept, unlike its position,
+ which changes as memories are made and pruned.
+
+ ULIDs sort by when they were made, so IDs still read in order.
+*/
+pub type MemoryId = ulid::Ulid;
 pub type UserProfile = String;
 pub type InteractionSummary = String;
 pub type Conversation = String;
+// Where a message came from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Repl,
+    Websocket
+}
+
+/*
+ A few turns of a memory's conversation, embedded on their own, so each
+ topic of a long conversation can be recalled by itself.
+
+ Only where it is in the conversation is kept, as a range of bytes,
+ rather than a copy of the text.
+*/
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Chunk {
+    pub start: usize,
+    pub end: usize,
+    pub embedding: Embedding
+}
+
+/*
+ An embedding, scaled to a magnitude of 1 and kept as f32 to halve its size.
+
+ Saved as base64 of its little-endian bytes rather than a list of numbers,
+ which keeps character files several times smaller.
+ Older files with plain lists of numbers are still read, and normalized on the way in.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Embedding(Vec<f32>);
+impl From<Vec<f64>> for Embedding {
+    fn from( values: Vec<f64> ) -> Self {
+        Self(linalg::normalize(&values)
+            .into_iter()
+            .map(|value| value as f32)
+            .collect())
+    }
+}
+impl Embedding {
+    pub fn to_bytes( &self ) -> Vec<u8> {
+        self.0.iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+    // None unless the bytes are a whole number of f32s.
+    pub fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+        if !bytes.len().is_multiple_of(4) {
+            return None;
+        }
+
+        Some(Self(bytes.chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect()))
+    }
+}
+impl std::ops::Deref for Embedding {
+    type Target = [f32];
+
+    fn deref( &self ) -> &[f32] {
+        &self.0
+    }
+}
+impl Serialize for Embedding {
+    fn serialize<S: serde::Serializer>( &self, serializer: S ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(self.to_bytes()))
+    }
+}
+impl<'de> Deserialize<'de> for Embedding {
+    fn deserialize<D: serde::Deserializer<'de>>( deserializer: D ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum StoredEmbedding {
+            Encoded(String),
+            Legacy(Vec<f64>)
+        }
+
+        match StoredEmbedding::deserialize(deserializer)? {
+            StoredEmbedding::Encoded(encoded) => {
+                let bytes = BASE64.decode(encoded)
+                    .map_err(serde::de::Error::custom)?;
+
+                Self::from_bytes(&bytes)
+                    .ok_or(serde::de::Error::custom("Embedding isn't a whole number of f32s"))
+            },
+            StoredEmbedding::Legacy(values) => Ok(Self::from(values))
+        }
+    }
+}
 impl Memory {
-    pub async fn new( conversation: String ) -> Self {
+    pub async fn new( conversation: String, providers: &llm::Providers ) -> Result<Self, llm::Error> {
         // Creates the time first, since the later parts can cause notable delay.
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -26,28 +139,57 @@ impl Memory {
          Generates the embeddings, user profile, and summary asynchronously.
          Finally, returns the generated Memory object.
         */
-        match tokio::try_join!(
-            Self::generate_embedding(&conversation),
-            Self::generate_user_profile(&conversation),
-            Self::generate_interaction_summary(&conversation)
-        ) {
-            Ok((embedding, user_profile, interaction_summary)) => {
-                return Self {
-                    embedding,
-                    user_profile, 
-                    interaction_summary,
-                    conversation,
-                    timestamp,
-                    times_read: 0usize
-                }
-            }
-            Err(_) => todo!()
-        }
+        let (chunks, user_profile, interaction_summary) = tokio::try_join!(
+            Self::generate_chunks(&conversation, providers),
+            Self::generate_user_profile(&conversation, providers),
+            Self::generate_interaction_summary(&conversation, providers)
+        )?;
+
+        let embedding = match chunks.as_slice() {
+            [] => Self::generate_embedding(&conversation, providers).await?,
+            [chunk] => chunk.embedding.clone(),
+            chunks => Embedding::from((0..chunks[0].embedding.len())
+                .map(|dimension| chunks.iter().map(|chunk| chunk.embedding.get(dimension).copied().unwrap_or(0f32) as f64).sum())
+                .collect::<Vec<f64>>())
+        };
+
+        Ok(Self {
+            id: MemoryId::new(),
+            embedding,
+            chunks,
+            embedding_model: providers.embedding.model().to_string(),
+            user_profile, 
+            interaction_summary,
+            conversation,
+            channels: Vec::new(),
+            participants: Vec::new(),
+            tags: Vec::new(),
+            timestamp,
+            times_read: 0usize,
+            last_accessed: None,
+            recalls: Vec::new()
+        })
     }
-    async fn generate_embedding( input: &String ) -> Result<Embedding, ()> {
-        Ok(openai::embedding_request(input).await.unwrap())
+    async fn generate_embedding( input: &str, providers: &llm::Providers ) -> Result<Embedding, llm::Error> {
+        Ok(Embedding::from(providers.embedding.embed(input).await?))
     }
-    async fn generate_user_profile( input: &String ) -> Result<UserProfile, ()> {
+    async fn generate_chunks( conversation: &str, providers: &llm::Providers ) -> Result<Vec<Chunk>, llm::Error> {
+        futures::future::try_join_all(chunk_ranges(conversation)
+            .into_iter()
+            .map(|range| async move {
+                let embedding = Self::generate_embedding(&conversation[range.clone()], providers).await?;
+
+                Ok::<Chunk, llm::Error>(Chunk { start: range.start, end: range.end, embedding })
+            }))
+            .await
+    }
+    // The text of one of the memory's chunks, or the whole conversation if there's no such chunk.
+    pub fn excerpt( &self, chunk: Option<usize> ) -> &str {
+        chunk.and_then(|chunk| self.chunks.get(chunk))
+            .and_then(|chunk| self.conversation.get(chunk.start..chunk.end))
+            .unwrap_or(&self.conversation)
+    }
+    async fn generate_user_profile( input: &str, providers: &llm::Providers ) -> Result<UserProfile, llm::Error> {
         let prompt = format!("
             In the following conversation, you are Monikai.
             Create a USER PROFILE detailing what you've learned about the MC from the following conversation.
@@ -62,9 +204,9 @@ impl Memory {
 
         ", input);
 
-        Ok(openai::instruction_request(prompt).await.unwrap())
+        providers.completion.complete(prompt).await
     }
-    async fn generate_interaction_summary( input: &String ) -> Result<InteractionSummary, ()> {
+    async fn generate_interaction_summary( input: &str, providers: &llm::Providers ) -> Result<InteractionSummary, llm::Error> {
         let prompt = format!("
             In the following conversation, you are Monikai.
             Create an INTERACTION SUMMARY detailing what you've learned about the MC from the following conversation.
@@ -79,7 +221,82 @@ impl Memory {
 
         ", input);
 
-        Ok(openai::instruction_request(prompt).await.unwrap())
+        providers.completion.complete(prompt).await
+    }
+    // Identifies a memory, using FNV-1a since it has to stay the same across builds.
+    pub fn key( &self ) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let bytes = self.timestamp.to_le_bytes().into_iter()
+            .chain((self.embedding.len() as u64).to_le_bytes())
+            .chain(self.conversation.bytes());
+
+        for byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        hash
+    }
+    // Counts a recall at 'time', in seconds since the Unix epoch.
+    pub fn recall( &mut self, time: u64 ) {
+        self.times_read += 1usize;
+        self.last_accessed = Some(time);
+        self.recalls.push(time);
+    }
+    /*
+     How many days it takes the memory to fade to 1/e, and when it was
+     last made or recalled, which it's been fading since.
+
+     Each recall multiplies the stability by up to 1 + 'STABILITY_GROWTH',
+     more so the more the memory had faded by then. So recalling something
+     twice in one conversation barely helps, while recalling it days later
+     does a lot, like spaced repetition.
+    */
+    pub fn stability( &self ) -> (f64, u64) {
+        // Reads from before recalls were recorded each put off forgetting by about a day, as they used to
+        let unrecorded_reads = self.times_read.saturating_sub(self.recalls.len());
+        let mut stability = INITIAL_STABILITY_DAYS + unrecorded_reads as f64 / (1f64 / FORGET_BELOW).ln();
+        let mut reinforced = self.timestamp;
+
+        for &recall in &self.recalls {
+            let days_since = recall.saturating_sub(reinforced) as f64 / 86400f64;
+            let retention = (-days_since / stability).exp();
+
+            stability *= 1f64 + STABILITY_GROWTH * (1f64 - retention);
+            reinforced = reinforced.max(recall);
+        }
+
+        (stability, reinforced)
+    }
+    // How much of the memory is left at 'time', from 1 when just made or recalled, fading towards 0.
+    pub fn retention( &self, time: u64 ) -> f64 {
+        let (stability, reinforced) = self.stability();
+        let days_since = time.saturating_sub(reinforced) as f64 / 86400f64;
+
+        (-days_since / stability).exp()
+    }
+    /*
+     Whether the memory has faded enough to be forgotten at 'time'.
+
+     Some memories are never forgotten, however long it's been,
+     which here is those that have grown stable over a year.
+    */
+    pub fn is_forgotten( &self, time: u64 ) -> bool {
+        self.stability().0 < PERMANENT_STABILITY_DAYS && self.retention(time) < FORGET_BELOW
+    }
+    // What lexical search looks through.
+    pub fn searchable_text( &self ) -> String {
+        format!("{}\n{}", self.conversation, self.interaction_summary)
+    }
+    // A copy with the embedding emptied, for printing.
+    pub fn without_embedding( &self ) -> Self {
+        Self {
+            embedding: Embedding::default(),
+            chunks: self.chunks.iter()
+                .map(|chunk| Chunk { embedding: Embedding::default(), ..chunk.clone() })
+                .collect(),
+            ..self.clone()
+        }
     }
     pub fn readable_time_since( &self ) -> String {
         let current_time = SystemTime::now()
@@ -98,4 +315,405 @@ impl Memory {
         }
         format!("{} seconds", seconds_since)
     }
-}
\ No newline at end of file
+}
+
+/*
+ The forgetting curve, after Ebbinghaus: a memory's retention falls as
+ e^(-t/S), t being the days since it was last made or recalled and S its
+ stability, and it's forgotten once that falls below 'FORGET_BELOW'.
+
+ A memory that's never recalled lasts about a week.
+*/
+pub const INITIAL_STABILITY_DAYS: f64 = 3f64;
+pub const STABILITY_GROWTH: f64 = 3f64;
+pub const FORGET_BELOW: f64 = 0.1;
+pub const PERMANENT_STABILITY_DAYS: f64 = 365f64;
+
+/*
+ Conversations are embedded 'CHUNK_TURNS' lines at a time, each chunk
+ starting 'CHUNK_OVERLAP' lines before the last one ended so no exchange
+ is split from its context.
+ Chunks are also kept under 'MAX_CHUNK_BYTES', well within what
+ embedding models take, splitting any longer line on its own.
+*/
+pub const CHUNK_TURNS: usize = 4;
+pub const CHUNK_OVERLAP: usize = 1;
+pub const MAX_CHUNK_BYTES: usize = 4000;
+
+// Where each chunk of a conversation starts and ends, in bytes.
+pub fn chunk_ranges( conversation: &str ) -> Vec<std::ops::Range<usize>> {
+    let mut lines: Vec<std::ops::Range<usize>> = Vec::new();
+    let mut line_start = 0;
+    for line in conversation.split('\n') {
+        let mut start = line_start;
+        let end = line_start + line.len();
+        line_start = end + 1;
+
+        while start < end {
+            let mut split = (start + MAX_CHUNK_BYTES).min(end);
+            while !conversation.is_char_boundary(split) {
+                split -= 1;
+            }
+
+            if !conversation[start..split].trim().is_empty() {
+                lines.push(start..split);
+            }
+            start = split;
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut first = 0;
+    while first < lines.len() {
+        let mut last = first;
+        while last + 1 < lines.len() && last + 1 - first < CHUNK_TURNS && lines[last + 1].end - lines[first].start <= MAX_CHUNK_BYTES {
+            last += 1;
+        }
+        chunks.push(lines[first].start..lines[last].end);
+
+        if last + 1 == lines.len() {
+            break;
+        }
+        first = (last + 1).saturating_sub(CHUNK_OVERLAP).max(first + 1);
+    }
+
+    chunks
+}
+
+// Below this many embeddings, comparing against every one is quick enough and exact.
+pub const EXACT_SCAN_LIMIT: usize = 512;
+// How many candidates the graph is asked for, before thresholding and re-ranking
+const EF_SEARCH: usize = 64;
+const INDEX_VERSION: u32 = 2;
+// How deep into each ranking reciprocal rank fusion looks, at the least
+const FUSION_DEPTH: usize = 20;
+
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+fn next_generation() -> u64 {
+    GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/*
+ A read-only view of the memories for recalling them by similarity,
+ kept apart from the memories themselves so searching never reorders them.
+
+ Each of a memory's chunks is a node of its own, or the memory's whole
+ embedding if it has no chunks, and a memory is as similar as its most
+ similar chunk.
+
+ Large memory banks are searched through an HNSW graph, rather than
+ compared one by one. Their words are also indexed, for searching by
+ exact names, titles and numbers that embeddings tend to miss.
+ 'sync' brings both up to date with the memories, only adding or
+ removing whatever changed.
+
+ The graph is saved along with the character, and is only reused if
+ it was built from the same memories. The words are quick enough to
+ index again on loading.
+*/
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MemoryIndex {
+    version: u32,
+    // In the same order as the memories
+    entries: Vec<IndexEntry>,
+    // The embedding length linked into the graph, anything else is scanned
+    dimensions: Option<usize>,
+    graph: hnsw::Hnsw,
+    // Keyed by each memory's first node
+    #[serde(skip)]
+    lexical: bm25::Bm25,
+    #[serde(skip)]
+    vectors: Vec<Vec<f32>>,
+    // Each node's memory, and chunk if it is one
+    #[serde(skip)]
+    positions: Vec<Option<(usize, Option<usize>)>>,
+    // Changes whenever the index does, so storage can tell if it needs saving
+    #[serde(skip)]
+    generation: u64
+}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    key: u64,
+    // One per chunk, in order, or just the one if 'chunked' isn't set
+    nodes: Vec<usize>,
+    chunked: bool
+}
+
+// What of a memory is linked in, one vector per node.
+fn memory_vectors( memory: &Memory ) -> Vec<Vec<f32>> {
+    if memory.chunks.is_empty() {
+        return vec![memory.embedding.to_vec()];
+    }
+
+    memory.chunks.iter()
+        .map(|chunk| chunk.embedding.to_vec())
+        .collect()
+}
+
+impl MemoryIndex {
+    pub fn build( memories: &[Memory] ) -> Self {
+        let mut index = Self {
+            version: INDEX_VERSION,
+            generation: next_generation(),
+            ..Self::default()
+        };
+        for memory in memories {
+            index.push(memory);
+        }
+        index.update_positions();
+
+        index
+    }
+    /*
+     Reads a saved index, if it matches the memories.
+
+     The vectors themselves aren't saved, since the memories already have them.
+    */
+    pub fn from_json( index_json: &str, memories: &[Memory] ) -> Option<Self> {
+        let mut index = serde_json::from_str::<Self>(index_json).ok()?;
+
+        let matches = index.version == INDEX_VERSION &&
+            index.entries.len() == memories.len() &&
+            index.entries.iter()
+                .zip(memories.iter())
+                .all(|(entry, memory)| entry.key == memory.key() && entry.nodes.len() == memory_vectors(memory).len());
+        if !matches {
+            return None;
+        }
+
+        let node_count = index.entries.iter().flat_map(|entry| entry.nodes.iter()).map(|node| node + 1).max().unwrap_or(0);
+        index.vectors = vec![Vec::new(); node_count];
+        for (entry, memory) in index.entries.iter().zip(memories.iter()) {
+            for (node, vector) in entry.nodes.iter().zip(memory_vectors(memory)) {
+                index.vectors[*node] = vector;
+            }
+            index.lexical.insert(entry.nodes[0], &memory.searchable_text());
+        }
+        index.update_positions();
+        index.generation = next_generation();
+
+        Some(index)
+    }
+    pub fn to_json( &self ) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn generation( &self ) -> u64 {
+        self.generation
+    }
+    fn push( &mut self, memory: &Memory ) {
+        let mut nodes = Vec::new();
+        for vector in memory_vectors(memory) {
+            let node = self.vectors.len();
+            if self.dimensions.is_none() && !vector.is_empty() {
+                self.dimensions = Some(vector.len());
+            }
+            let linked = self.dimensions == Some(vector.len());
+
+            self.vectors.push(vector);
+            if linked {
+                self.graph.insert(node, &self.vectors);
+            }
+            nodes.push(node);
+        }
+
+        self.lexical.insert(nodes[0], &memory.searchable_text());
+        self.entries.push(IndexEntry { key: memory.key(), nodes, chunked: !memory.chunks.is_empty() });
+    }
+    fn update_positions( &mut self ) {
+        self.positions = vec![None; self.vectors.len()];
+
+        for (position, entry) in self.entries.iter().enumerate() {
+            for (chunk, node) in entry.nodes.iter().enumerate() {
+                self.positions[*node] = Some((position, entry.chunked.then_some(chunk)));
+            }
+        }
+    }
+    fn nodes( &self ) -> impl Iterator<Item = usize> + '_ {
+        self.entries.iter().flat_map(|entry| entry.nodes.iter().copied())
+    }
+    /*
+     Brings the index up to date with the memories.
+
+     Memories are matched up in order, so new memories at the end are
+     linked in and missing ones are unlinked, leaving the rest as is.
+     Anything more drastic is rebuilt from scratch.
+    */
+    pub fn sync( &mut self, memories: &[Memory] ) {
+        let keys: Vec<u64> = memories.iter().map(Memory::key).collect();
+        if self.entries.len() == keys.len() && self.entries.iter().zip(keys.iter()).all(|(entry, key)| entry.key == *key) {
+            return;
+        }
+
+        let mut matched = 0;
+        let mut removed: Vec<usize> = Vec::new();
+        for (position, entry) in self.entries.iter().enumerate() {
+            if keys.get(matched) == Some(&entry.key) {
+                matched += 1;
+            } else {
+                removed.push(position);
+            }
+        }
+
+        if self.version != INDEX_VERSION || removed.len() > self.entries.len() / 2 {
+            *self = Self::build(memories);
+            return;
+        }
+
+        for position in removed.into_iter().rev() {
+            let entry = self.entries.remove(position);
+
+            for node in &entry.nodes {
+                self.graph.remove(*node, &self.vectors);
+                self.vectors[*node] = Vec::new();
+            }
+            self.lexical.remove(entry.nodes[0]);
+        }
+        for memory in &memories[matched..] {
+            self.push(memory);
+        }
+
+        // Once the holes outnumber the nodes, close them up
+        let node_count = self.nodes().count();
+        if self.vectors.len() > 2 * node_count {
+            let mut mapping: Vec<Option<usize>> = vec![None; self.vectors.len()];
+            for (new, node) in self.entries.iter_mut().flat_map(|entry| entry.nodes.iter_mut()).enumerate() {
+                mapping[*node] = Some(new);
+                *node = new;
+            }
+
+            self.graph.renumber(&mapping);
+            self.lexical.renumber(&mapping);
+            let mut vectors = vec![Vec::new(); node_count];
+            for (old, new) in mapping.iter().enumerate() {
+                if let Some(new) = new {
+                    vectors[*new] = std::mem::take(&mut self.vectors[old]);
+                }
+            }
+            self.vectors = vectors;
+        }
+
+        self.update_positions();
+        self.generation = next_generation();
+    }
+    fn similarity( &self, node: usize, query: &[f32] ) -> Option<f64> {
+        linalg::dot_product_f32( query, &self.vectors[node] ).ok()
+            .map(|similarity| (similarity as f64).clamp(-1f64, 1f64))
+    }
+    /*
+     Ranks the memories most related to an embedding, as (index, similarity) pairs.
+
+     Only memories with a chunk at least 'min_similarity' alike are considered,
+     and at most 'top_k' are returned, re-ranked for diversity if 'mmr_lambda' is set.
+    */
+    pub fn search( &self, query: &Embedding, config: &RetrievalConfig ) -> Vec<(usize, f64)> {
+
+        let nodes: Vec<usize> = if self.nodes().count() <= EXACT_SCAN_LIMIT || self.dimensions != Some(query.len()) {
+            self.nodes().collect()
+        } else {
+            // Embeddings of any other length aren't in the graph, so are still checked by hand
+            self.graph.search(query, config.top_k.max(EF_SEARCH), EF_SEARCH, &self.vectors)
+                .into_iter()
+                .map(|(node, _)| node)
+                .chain(self.nodes().filter(|node| !self.graph.contains(*node)))
+                .collect()
+        };
+
+        let mut candidates: Vec<(usize, f64)> = nodes.into_iter()
+            .filter_map(|node| self.similarity(node, query).map(|similarity| (node, similarity)))
+            .filter(|(_, similarity)| *similarity >= config.min_similarity)
+            .collect();
+        candidates.sort_by(|(_, a_sim), (_, b_sim)| b_sim.total_cmp(a_sim));
+
+        // Each memory only by its most similar chunk
+        let mut seen: HashSet<usize> = HashSet::new();
+        candidates.retain(|(node, _)| seen.insert(self.positions[*node].unwrap().0));
+
+        let mut selected: Vec<(usize, f64)> = match config.mmr_lambda {
+            None => {
+                candidates.truncate(config.top_k);
+                candidates
+            },
+            /*
+             Maximal marginal relevance - greedily picks whichever candidate is most
+             relevant to the embedding, less how alike it is to those already picked.
+            */
+            Some(lambda) => {
+                let mut selected: Vec<(usize, f64)> = Vec::new();
+                while selected.len() < config.top_k && !candidates.is_empty() {
+                    let (best, _) = candidates.iter()
+                        .enumerate()
+                        .map(|(position, (node, similarity))| {
+                            let redundancy = selected.iter()
+                                .filter_map(|(selected_node, _)| self.similarity(*node, &self.vectors[*selected_node]))
+                                .fold(0f64, f64::max);
+
+                            (position, lambda * similarity - (1f64 - lambda) * redundancy)
+                        })
+                        .max_by(|(_, a_score), (_, b_score)| a_score.total_cmp(b_score))
+                        .unwrap();
+
+                    selected.push(candidates.remove(best));
+                }
+
+                selected
+            }
+        };
+
+        for (node, _) in selected.iter_mut() {
+            *node = self.positions[*node].unwrap().0;
+        }
+
+        selected
+    }
+    // Which of a memory's chunks is most similar to an embedding, if it has any.
+    pub fn best_chunk( &self, index: usize, query: &Embedding ) -> Option<usize> {
+        self.entries.get(index)?.nodes.iter()
+            .filter_map(|node| self.similarity(*node, query).map(|similarity| (*node, similarity)))
+            .max_by(|(_, a_sim), (_, b_sim)| a_sim.total_cmp(b_sim))
+            .and_then(|(node, _)| self.positions[node].unwrap().1)
+    }
+    /*
+     Ranks the memories most related to a key phrase, as (index, score) pairs,
+     by both its embedding and its words.
+
+     The two rankings are fused with reciprocal rank fusion, each memory
+     scoring 1/('rrf_k' + rank) for each ranking it's in. So a memory only
+     one of them finds can still be recalled, and one both find comes first.
+     Without 'lexical_search', this is just 'search'.
+
+     'min_similarity' only holds back the embedding's ranking. Memories
+     found by their words are held back by 'min_term_match' instead, the
+     share of the key phrase's words they must contain, since what they're
+     found for is often a name or number the embedding misses.
+    */
+    pub fn hybrid_search( &self, text: &str, query: &Embedding, config: &RetrievalConfig ) -> Vec<(usize, f64)> {
+        if !config.lexical_search {
+            return self.search(query, config);
+        }
+
+        let depth = config.top_k.max(FUSION_DEPTH);
+        let semantic = self.search(query, &RetrievalConfig { top_k: depth, ..config.clone() });
+        let lexical: Vec<usize> = self.lexical.search(text, depth)
+            .into_iter()
+            .filter(|(node, _)| self.lexical.coverage(*node, text) >= config.min_term_match)
+            .filter_map(|(node, _)| self.positions[node].map(|(position, _)| position))
+            .collect();
+
+        let mut fused: Vec<(usize, f64)> = Vec::new();
+        let rankings = [semantic.into_iter().map(|(index, _)| index).collect::<Vec<usize>>(), lexical];
+        for ranking in rankings {
+            for (rank, index) in ranking.into_iter().enumerate() {
+                let score = 1f64 / (config.rrf_k + rank as f64 + 1f64);
+
+                match fused.iter_mut().find(|(fused_index, _)| *fused_index == index) {
+                    Some(existing) => existing.1 += score,
+                    None => fused.push((index, score))
+                }
+            }
+        }
+        fused.sort_by(|(a_index, a_score), (b_index, b_score)| b_score.total_cmp(a_score).then(a_index.cmp(b_index)));
+        fused.truncate(config.top_k);
+
+        fused
+    }
+}
@@ -1,23 +1,30 @@
-use std::fs::{ File };
-use std::io::{ Write, Seek };
-use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 use axum::{
     extract::ws::{WebSocketUpgrade, WebSocket},
-    routing::get,
+    extract::{ Path as AxumPath, State },
+    http::StatusCode,
+    extract::Query,
+    routing::{ get, post },
     response::{ Html},
+    Json,
     Router,
 };
 use tower_http::services::ServeDir;
 use futures::{sink::SinkExt, stream::StreamExt};
+use tokio::io::{ AsyncBufReadExt, BufReader };
+use tokio::sync::{ mpsc, watch };
 use tokio::time::sleep;
 
 use crate::{ Serialize, Deserialize };
 use crate::{ Mutex, Arc };
-use crate::OpenOptions;
+use crate::config::{ PromptConfig, RetrievalConfig, ServerConfig, TimingConfig };
+use crate::storage::{ migrations, Storage, StorageError, StorageHandle, StorageRequest };
 use crate::memory;
-use crate::openai; 
-use crate::linalg;
+use crate::llm;
 use crate::print;
+use crate::prompt;
+use crate::profile;
+use crate::knowledge;
 
 #[derive(Debug, Deserialize)]
 struct MemoryDiveConformation {
@@ -25,43 +32,102 @@ struct MemoryDiveConformation {
     memory_check_phrase: String
 }
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Monikai {
+    // Files from before there was one don't have it, see 'storage::migrations'
+    #[serde(default)]
+    pub schema_version: u32,
     pub description: String,
+    // What's been learned about the MC, merged from every memory
+    pub profile: profile::Profile,
+    // Facts about the MC and their life, extracted from every memory
+    pub knowledge: knowledge::Knowledge,
     pub memories: Vec<memory::Memory>,
-    pub current_conversation: Vec<openai::Message>,
-    pub last_spoken_to: u64
+    pub current_conversation: Vec<llm::Message>,
+    // Where the current conversation has been had
+    pub conversation_channels: Vec<memory::Channel>,
+    pub last_spoken_to: u64,
+    #[serde(skip)]
+    pub providers: llm::Providers,
+    #[serde(skip)]
+    pub retrieval: RetrievalConfig,
+    #[serde(skip)]
+    pub prompt: PromptConfig,
+    #[serde(skip)]
+    pub index: memory::MemoryIndex,
+    // How many of the conversation's first turns have been summarized to fit the prompt, and the summary
+    #[serde(skip)]
+    pub conversation_summary: (usize, String),
+    // Bumped on every change, so autosave can tell when there's something to save
+    #[serde(skip)]
+    pub version: u64
+}
+impl Default for Monikai {
+    fn default() -> Self {
+        Self {
+            schema_version: migrations::SCHEMA_VERSION,
+            description: String::new(),
+            profile: profile::Profile::default(),
+            knowledge: knowledge::Knowledge::default(),
+            memories: Vec::new(),
+            current_conversation: Vec::new(),
+            conversation_channels: Vec::new(),
+            last_spoken_to: 0u64,
+            providers: llm::Providers::default(),
+            retrieval: RetrievalConfig::default(),
+            prompt: PromptConfig::default(),
+            index: memory::MemoryIndex::default(),
+            conversation_summary: (0, String::new()),
+            version: 0
+        }
+    }
 }
 impl Monikai {
-    async fn respond( &mut self ) -> String {
+    async fn respond( &mut self, deltas: Option<mpsc::UnboundedSender<String>> ) -> Result<String, llm::Error> {
         // Set the last spoken to timestamp to now
         self.last_spoken_to = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
         
-        // First, compile the conversation and user profile
-        let mut messages = self.current_conversation.clone();
-        let user_profile = self.memories.iter()
-            .map(|memory| memory.user_profile.clone() )
-            .collect::<Vec<String>>()
-            .join("\n");
+        // First, work out how much of the context each part of the prompt may take
+        let tokenizer = prompt::Tokenizer::for_model(self.providers.chat.model());
+        let budget = prompt::Budget::allocate(&self.prompt, self.providers.chat.model(), tokenizer.count(&self.description));
+        let latest_message = self.current_conversation.iter().last().unwrap().content.clone();
+
+        // Then the user profile, most recently learned first, for as long as it fits
+        let profile_intro = "The following is information about MC you have gathered from previous conversations.\n";
+        let profile_lines: Vec<String> = self.profile.lines().collect();
+        let mut user_profile = prompt::fit_latest(
+            profile_lines.iter().map(String::as_str),
+            budget.profile.saturating_sub(tokenizer.count(profile_intro)),
+            &tokenizer);
+
+        // Along with any known facts the latest message asks about, the most relevant first, out of what the profile left
+        let facts_intro = "\nFacts you know for certain:\n";
+        let fact_lines: Vec<String> = self.knowledge.relevant(&latest_message).iter()
+            .map(|fact| format!("- {}", fact))
+            .collect();
+        let known_facts = prompt::fit_latest(
+            fact_lines.iter().rev().map(String::as_str),
+            budget.profile.saturating_sub(tokenizer.count(profile_intro) + tokenizer.count(&user_profile) + tokenizer.count(facts_intro)),
+            &tokenizer);
+        if !known_facts.is_empty() {
+            user_profile = format!("{}{}{}", user_profile, facts_intro, known_facts);
+        }
 
         // Next, insert the nessecary context about who the Monikai is
-        messages.insert(
-            0, 
-            openai::Message { 
-                role: String::from("system"), 
-                content: self.description.clone()
+        let mut messages = vec!(
+            llm::Message {
+                role: String::from("system"),
+                content: tokenizer.truncate(&self.description, budget.description)
+            },
+            llm::Message {
+                role: String::from("system"),
+                content: format!("{}{}", profile_intro, user_profile)
             });
+        let mut unused = budget.profile.saturating_sub(tokenizer.count(&messages[1].content));
 
-        // Insert the user profile context
-        messages.insert(
-            1, 
-            openai::Message { 
-                role: String::from("system"), 
-                content: format!("The following is information about MC you have gathered from previous conversations. {}", user_profile)
-            });
-        
         // Build the prompt to check if more context is needed to respond
         let manual_memory_check_prompt = format!("
             In the following conversation, you are 'assistant' (or Monikai).
@@ -82,7 +148,7 @@ impl Monikai {
 
             Response:
             {{
-            ", user_profile, messages.iter().last().unwrap().content);
+            ", user_profile, latest_message);
         let automatic_memory_check_prompt = format!("
             In the following conversation, you are the 'assistant' (or Monikai).
             Generate an incredibly short phrase to check your memory embeddings for similar things to the current conversation.
@@ -102,99 +168,378 @@ impl Monikai {
             Response:
             {{
                 \"needs_memory_check\": true,
-            ", user_profile, messages.iter().last().unwrap().content);
+            ", user_profile, latest_message);
 
         // Prompt davinci-003 to generate keyphrases
-        let manual_memory_check_unparsed = openai::instruction_request(manual_memory_check_prompt).await.unwrap();
-        let automatic_memory_check_unparsed = openai::instruction_request(automatic_memory_check_prompt).await.unwrap();
+        let manual_memory_check_unparsed = self.providers.completion.complete(manual_memory_check_prompt).await?;
+        let automatic_memory_check_unparsed = self.providers.completion.complete(automatic_memory_check_prompt).await?;
 
-        // If the input parses, try to grab context.
+        // If either check parses, recall whatever memories relate to its key phrase
+        let mut key_phrases = Vec::new();
         if let Ok(memory_check) = serde_json::from_str::<MemoryDiveConformation>(format!("{{\"needs_memory_check\": true, {}", automatic_memory_check_unparsed).as_str()) {
-            let key_phrase_embedding = openai::embedding_request(&memory_check.memory_check_phrase).await.unwrap();
+            key_phrases.push(memory_check.memory_check_phrase);
+        }
+        if let Ok(memory_check) = serde_json::from_str::<MemoryDiveConformation>(format!("{{{}", manual_memory_check_unparsed).as_str()) {
+            if memory_check.needs_memory_check {
+                key_phrases.push(memory_check.memory_check_phrase);
+            }
+        }
 
-            self.memories
-                .sort_by(|a, b| {
-                    let a_sim = linalg::cosine_similarity(&key_phrase_embedding, &a.embedding);
-                    let b_sim = linalg::cosine_similarity(&key_phrase_embedding, &b.embedding);
+        // Each memory along with the chunk of it most related to whichever key phrase it was most related to
+        let mut recalled: Vec<(usize, f64, Option<usize>)> = Vec::new();
+        for key_phrase in key_phrases {
+            let key_phrase_embedding = self.providers.embedding.embed(&key_phrase).await?;
+
+            for (index, score, chunk) in self.recall(&key_phrase, &key_phrase_embedding) {
+                match recalled.iter_mut().find(|(recalled_index, _, _)| *recalled_index == index) {
+                    Some(existing) if score > existing.1 => *existing = (index, score, chunk),
+                    Some(_) => {},
+                    None => recalled.push((index, score, chunk))
+                }
+            }
+        }
+        recalled.sort_by(|(_, a_score, _), (_, b_score, _)| b_score.total_cmp(a_score));
+
+        // Add the most related memories first, for as long as they fit
+        let memories_intro = "You believe you may need additional information to respond. Here are related memories:";
+        let mut memory_tokens = tokenizer.count(memories_intro);
+        let mut recalled_memories = String::new();
+        for (index, _, chunk) in recalled {
+            let memory = &mut self.memories[index];
+            let recalled_memory = format!("\n- From {} ago: {}", memory.readable_time_since(), memory.excerpt(chunk));
+            let recalled_tokens = tokenizer.count(&recalled_memory);
+
+            if recalled_memories.len() + recalled_memory.len() > self.retrieval.max_memory_chars || memory_tokens + recalled_tokens > budget.memories {
+                continue;
+            }
 
-                    a_sim.partial_cmp(&b_sim).unwrap()
+            memory.recall(self.last_spoken_to);
+            memory_tokens += recalled_tokens;
+            recalled_memories.push_str(&recalled_memory);
+        }
+        if !recalled_memories.is_empty() {
+            messages.push(
+                llm::Message { 
+                    role: String::from("system"), 
+                    content: format!("{}{}", memories_intro, recalled_memories)
                 });
 
-            if let Some(most_similar) = self.memories.last_mut() {
-                most_similar.times_read += 1usize;
-
-                messages.insert(
-                    2, 
-                    openai::Message { 
-                        role: String::from("system"), 
-                        content: format!("You believe you may need additional information to respond. Here is a related memory from {} ago: {}", most_similar.readable_time_since(), most_similar.conversation)
-                    });
-                
-                print::debug("Grabbed automatic memory");
-            }
+            print::debug("Grabbed related memories");
         }
-        if let Ok(memory_check) = serde_json::from_str::<MemoryDiveConformation>(format!("{{{}", manual_memory_check_unparsed).as_str()) {
-            if memory_check.needs_memory_check {
-                let key_phrase_embedding = openai::embedding_request(&memory_check.memory_check_phrase).await.unwrap();
-
-                self.memories
-                    .sort_by(|a, b| {
-                        let a_sim = linalg::cosine_similarity(&key_phrase_embedding, &a.embedding);
-                        let b_sim = linalg::cosine_similarity(&key_phrase_embedding, &b.embedding);
-
-                        a_sim.partial_cmp(&b_sim).unwrap()
-                    });
-
-                if let Some(most_similar) = self.memories.last_mut() {
-                    most_similar.times_read += 1usize;
-
-                    messages.insert(
-                        2, 
-                        openai::Message { 
-                            role: String::from("system"), 
-                            content: format!("You believe you may need additional information to respond. Here is a related memory from {} ago: {}", most_similar.readable_time_since(), most_similar.conversation)
-                        });
-                    
-                    print::debug("Grabbed manually checked memory");
-                }
+        unused += budget.memories.saturating_sub(if recalled_memories.is_empty() { 0 } else { memory_tokens });
+
+        // Finally, the conversation, with whatever the rest left over
+        let (first_turn, turn_budget) = self.fit_conversation(budget.conversation + unused, &tokenizer).await?;
+        if first_turn > 0 {
+            messages.push(
+                llm::Message {
+                    role: String::from("system"),
+                    content: format!("Earlier in this conversation: {}", self.conversation_summary.1)
+                });
+        }
+        messages.extend(self.current_conversation[first_turn..].iter().cloned());
+        if let Some(latest) = messages.last_mut() {
+            latest.content = tokenizer.truncate(&latest.content, turn_budget);
+        }
+
+        // And prompt the model, streaming the response if asked to
+        let response = match deltas {
+            Some(deltas) => self.providers.chat.chat_stream( messages, deltas ).await?.content,
+            None => {
+                let response = self.providers.chat.chat( messages ).await?.content;
+                print::monikai(&response);
+
+                response
             }
+        };
+
+        self.current_conversation.push( llm::Message { role: String::from("assistant"), content: response.clone() } );
+
+        Ok(response)
+    }
+    /*
+     Where the turns of the conversation that fit in 'budget' tokens start,
+     and how many tokens they may take, summarizing any that don't fit.
+
+     Summaries reach further back than they need to, so the conversation
+     has room to grow before the next one. The completion model summarizes
+     as many turns at a time as fit in its own context, building on the
+     summary so far, and a turn too long by itself keeps only its end.
+    */
+    async fn fit_conversation( &mut self, budget: usize, tokenizer: &prompt::Tokenizer ) -> Result<(usize, usize), llm::Error> {
+        let summary_budget = budget / 8;
+        let turn_budget = budget - summary_budget;
+
+        let first_turn = prompt::first_fitting(&self.current_conversation, turn_budget, tokenizer);
+        if first_turn == 0 {
+            return Ok((0, budget));
         }
 
-        // Finally, prompt the model
-        let response = openai::turbo_request( messages ).await.unwrap().content;
+        if self.conversation_summary.0 >= first_turn {
+            return Ok((self.conversation_summary.0, turn_budget));
+        }
 
-        print::monikai(&response);
+        let summarized = prompt::first_fitting(&self.current_conversation, turn_budget / 2, tokenizer).max(first_turn);
+        let completion_tokenizer = prompt::Tokenizer::for_model(self.providers.completion.model());
+        let completion_tokens = self.prompt.completion_context_tokens
+            .unwrap_or_else(|| prompt::context_size(self.providers.completion.model()))
+            .saturating_sub(self.providers.completion.max_tokens());
+        while self.conversation_summary.0 < summarized {
+            let (previously_summarized, previous_summary) = &self.conversation_summary;
+            let batch_budget = completion_tokens.saturating_sub(completion_tokenizer.count(&summary_prompt(previous_summary, "")));
+
+            // Always at least one turn, so each batch gets somewhere
+            let mut batch_end = *previously_summarized;
+            let mut used = 0;
+            for turn in &self.current_conversation[*previously_summarized..summarized] {
+                let tokens = completion_tokenizer.count(&turn.to_string()) + 1;
+                if batch_end > *previously_summarized && used + tokens > batch_budget {
+                    break;
+                }
 
-        self.current_conversation.push( openai::Message { role: String::from("assistant"), content: response.clone() } );
+                used += tokens;
+                batch_end += 1;
+            }
+            let turns = self.current_conversation[*previously_summarized..batch_end]
+                .iter()
+                .map(|message| message.to_string())
+                .collect::<Vec<String>>()
+                .join("\n");
 
-        response
+            let summary = self.providers.completion.complete(summary_prompt(previous_summary, &completion_tokenizer.tail(&turns, batch_budget))).await?;
+
+            self.conversation_summary = (batch_end, tokenizer.truncate(&summary, summary_budget));
+            print::debug("Summarized the start of the conversation");
+        }
+
+        Ok((self.conversation_summary.0, turn_budget))
     }
-    pub async fn send_message( &mut self, message: String ) -> String {
-        self.current_conversation.push( openai::Message { role: String::from("user"), content: message } );
+    /*
+     Sends a message to the Monikai and returns its response.
+
+     If 'deltas' is given, each piece of the response is also sent down it
+     as it is generated, rather than printed once finished.
+
+     If the response can't be generated, the message is taken back out
+     of the conversation so that it can simply be sent again.
+    */
+    pub async fn send_message( &mut self, message: String, channel: memory::Channel, deltas: Option<mpsc::UnboundedSender<String>> ) -> Result<String, llm::Error> {
+        self.current_conversation.push( llm::Message { role: String::from("user"), content: message } );
+
+        let response = self.respond( deltas ).await;
+        match response {
+            Ok(_) if !self.conversation_channels.contains(&channel) => self.conversation_channels.push(channel),
+            Ok(_) => {},
+            Err(_) => { self.current_conversation.pop(); }
+        }
+        self.touch();
 
-        self.respond().await
+        response
     }
-    pub async fn end_conversation( &mut self ) {
+    /*
+     Encodes the current conversation into a new memory, returning its ID,
+     extracts the facts it states, and merges whatever it taught into the
+     user profile.
+
+     On failure, the conversation is left untouched so it can be retried.
+    */
+    pub async fn end_conversation( &mut self ) -> Result<memory::MemoryId, llm::Error> {
         let conversation_as_string: String = self.current_conversation
             .iter()
             .map(|message| message.to_string() )
             .collect::<Vec<String>>()
             .join("\n");
         
-        let new_memory = memory::Memory::new( conversation_as_string ).await;
+        let mut new_memory = memory::Memory::new( conversation_as_string, &self.providers ).await?;
+        let extracted_facts = self.providers.completion.complete(knowledge::Knowledge::extraction_prompt(&new_memory)).await?;
+        let merged_profile = self.providers.completion.complete(self.profile.merge_prompt(&new_memory)).await?;
+
+        // Nothing can fail from here on, so the memory and what it taught are kept together
+        match self.knowledge.learned(&extracted_facts, &new_memory) {
+            Some(knowledge) => self.knowledge = knowledge,
+            None => print::debug("Unable to read the extracted facts, so none were learned")
+        }
+        match self.profile.merged(&merged_profile, &new_memory) {
+            Some(profile) => self.profile = profile,
+            None => print::debug("Unable to read the merged profile, so it was left as it was")
+        }
+        new_memory.channels = std::mem::take(&mut self.conversation_channels);
+        for message in &self.current_conversation {
+            if !new_memory.participants.contains(&message.role) {
+                new_memory.participants.push(message.role.clone());
+            }
+        }
+        let id = new_memory.id;
 
         self.memories.push(new_memory);
+        self.index.sync(&self.memories);
         self.current_conversation = Vec::new();
+        self.conversation_summary = (0, String::new());
+        self.touch();
+
+        Ok(id)
+    }
+    pub fn memory( &self, id: memory::MemoryId ) -> Option<&memory::Memory> {
+        self.memories.iter().find(|memory| memory.id == id)
+    }
+    /*
+     Removes a memory for good, along with anything only it taught the profile
+     or the facts, returning it if there was one.
+    */
+    pub fn forget( &mut self, id: memory::MemoryId ) -> Option<memory::Memory> {
+        let position = self.memories.iter().position(|memory| memory.id == id)?;
+        let forgotten = self.memories.remove(position);
+        self.profile.forget(id);
+        self.knowledge.forget(id);
+
+        self.index.sync(&self.memories);
+        self.touch();
+
+        Some(forgotten)
     }
-    pub fn save_to_file( &self, file_handle: &mut File ) {
-        let self_as_string: String = serde_json::to_string_pretty(&self).unwrap();
+    /*
+     Forgets every memory that has faded by now, returning how many did.
+
+     Like 'forget', what was learned only from them goes with them.
+    */
+    pub fn prune( &mut self, current_time: u64 ) -> usize {
+        let mut pruned = Vec::new();
+        self.memories.retain(|memory| {
+            if memory.is_forgotten(current_time) {
+                print::debug(&format!("Pruned memory {}: {}...", memory.id, memory.interaction_summary.chars().take(35).collect::<String>()));
+                pruned.push(memory.id);
+
+                return false;
+            }
+
+            true
+        });
+        if pruned.is_empty() {
+            return 0;
+        }
 
-        file_handle.set_len(0).unwrap();
-        file_handle.rewind().unwrap();
-        file_handle.write_all(self_as_string.as_bytes()).expect("Failed to write!");
+        for id in &pruned {
+            self.profile.forget(*id);
+            self.knowledge.forget(*id);
+        }
+        self.index.sync(&self.memories);
+        self.touch();
+
+        pruned.len()
+    }
+    // Adds tags to a memory, skipping any it already has.
+    pub fn tag( &mut self, id: memory::MemoryId, tags: &[String] ) -> Option<&memory::Memory> {
+        let position = self.memories.iter().position(|memory| memory.id == id)?;
+
+        let memory = &mut self.memories[position];
+        for tag in tags {
+            if !memory.tags.contains(tag) {
+                memory.tags.push(tag.clone());
+            }
+        }
+        self.touch();
+
+        self.memories.get(position)
+    }
+    // Corrects a fact by hand, returning it as it now is.
+    pub fn edit_fact( &mut self, id: knowledge::FactId, subject: &str, predicate: &str, object: &str ) -> Option<&knowledge::Fact> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.knowledge.fact(id)?;
+        self.touch();
+
+        self.knowledge.edit(id, subject, predicate, object, now)
+    }
+    // Removes a fact, returning it if there was one.
+    pub fn unlearn( &mut self, id: knowledge::FactId ) -> Option<knowledge::Fact> {
+        let fact = self.knowledge.remove(id)?;
+        self.touch();
+
+        Some(fact)
+    }
+    // Marks the Monikai as changed, and in need of saving.
+    pub fn touch( &mut self ) {
+        self.version += 1;
+    }
+    /*
+     Indices of the memories most related to a key phrase and its embedding,
+     with their scores and whichever of their chunks is most related.
+
+     Catches the index up first if needed.
+    */
+    pub fn recall( &mut self, key_phrase: &str, embedding: &[f64] ) -> Vec<(usize, f64, Option<usize>)> {
+        self.index.sync(&self.memories);
+
+        let embedding = memory::Embedding::from(embedding.to_vec());
+        self.index.hybrid_search(key_phrase, &embedding, &self.retrieval)
+            .into_iter()
+            .map(|(index, score)| (index, score, self.index.best_chunk(index, &embedding)))
+            .collect()
+    }
+    /*
+     Puts the memories back in the order they were made.
+
+     Older versions reordered them by similarity on every message,
+     so files they saved need this after loading.
+    */
+    pub fn sort_memories( &mut self ) {
+        self.memories.sort_by_key(|memory| memory.timestamp);
+    }
+    // A copy with every embedding emptied, for printing.
+    pub fn without_embeddings( &self ) -> Self {
+        let mut monikai_no_embeddings = self.clone();
+
+        for memory in monikai_no_embeddings.memories.iter_mut() {
+            *memory = memory.without_embedding();
+        }
+
+        monikai_no_embeddings
+    }
+    /*
+     Adds memories from another character, skipping any this one already has.
+
+     Returns how many were added.
+    */
+    pub fn merge_memories( &mut self, memories: Vec<memory::Memory> ) -> usize {
+        let mut merged_count = 0;
+
+        for memory in memories {
+            let already_known = self.memories.iter()
+                .any(|known| (!memory.id.is_nil() && known.id == memory.id) || (known.timestamp == memory.timestamp && known.conversation == memory.conversation));
+
+            if !already_known {
+                self.memories.push(memory);
+                merged_count += 1;
+            }
+        }
+        self.sort_memories();
+        if merged_count > 0 {
+            self.touch();
+        }
+
+        merged_count
     }
 }
 
+// Asks for the summary so far to be carried on through some more turns.
+fn summary_prompt( earlier_summary: &str, turns: &str ) -> String {
+    format!("
+        In the following conversation, you are Monikai.
+        Create a SUMMARY of the conversation so far, in a few sentences, keeping any names, titles and numbers.
+
+        EARLIER SUMMARY:
+        {}
+
+        CONVERSATION:
+        {}
+
+        SUMMARY:
+
+    ", earlier_summary, turns)
+}
+
 /* 
  A Read-Eval-Print Loop (REPL) for Monikai.
  Probably the least convoluted method of communication.
@@ -202,95 +547,252 @@ impl Monikai {
  Saying something that's not a command forwards said message to the Monikai.
 
  Commands:
-  'wipe': Clear the Monikai's memories and recent conversation, preserves the description.
-  'save': Writes the Monikai in memory to 'monikai.json'.
+  'wipe': Clear the Monikai's memories, profile, facts and recent conversation, preserves the description.
+  'save': Writes the Monikai in memory to the character file.
   'end': Manually marks the current conversation as completed and encodes it as a memory.
   'log': Prints the Monikai in memory to stdout.
-  'get': Takes another line as input, and prints the memory most similar in cosine.
+  'profile': Prints what the Monikai has learned about the MC, and from which memories.
+  'facts': Takes another line as input, and prints the facts mentioning it, or every fact for an empty line.
+  'fact': Takes a fact's ID, then replaces it with a 'subject | predicate | object' line, or removes it for an empty line.
+  'get': Takes another line as input, and prints the memories most related to it, by meaning and by words.
+  'find': Takes another line as input, and prints the saved memories mentioning it.
+  'memory': Takes a memory's ID, and prints that memory.
+  'forget': Takes a memory's ID, and forgets that memory.
+  'tag': Takes a memory's ID, then a comma-separated list of tags to add to it.
 */
-pub async fn monikai_repl( monikai: Arc<Mutex<Monikai>> ) {
-    let mut character_file_handle: File = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("data/monikai.json")
-        .expect("Unable to get handle on './data/monikai.json'!");
-
-    let stdin = std::io::stdin();
-    let mut buffer = String::new();
+pub async fn monikai_repl( monikai: Arc<Mutex<Monikai>>, storage: StorageHandle, shutdown: Shutdown ) {
+    // Read stdin asynchronously, so that waiting on input doesn't tie up a worker thread
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
 
     loop {
-        stdin.read_line(&mut buffer).unwrap();
-
-        // Remove the trailing '\n' character
-        buffer = buffer
-            .split("\n")
-            .nth(0)
-            .unwrap()
-            .to_string();
+        let buffer = tokio::select! {
+            line = stdin.next_line() => match line {
+                Ok(Some(buffer)) => buffer,
+                _ => break
+            },
+            _ = shutdown.wait() => break
+        };
 
         // Check for any commandsx
         match buffer.as_str() {
             "clear" => panic!("This isn't a terminal, you know..."),
             "wipe" => {
-                let description = monikai.lock().await.description.clone();
+                let mut monikai = monikai.lock().await;
 
-                *monikai.lock().await = Monikai { 
-                    description, 
-                    memories: Vec::new(), 
-                    current_conversation: Vec::new(),
-                    last_spoken_to: 0u64
-                };
+                monikai.memories.clear();
+                monikai.profile = profile::Profile::default();
+                monikai.knowledge = knowledge::Knowledge::default();
+                monikai.current_conversation.clear();
+                monikai.conversation_channels.clear();
+                monikai.conversation_summary = (0, String::new());
+                monikai.last_spoken_to = 0u64;
+                monikai.touch();
 
                 print::info("Wiped");
             },
             "save" => {
-                monikai.lock().await.save_to_file(&mut character_file_handle);
-                print::info("Saved");
+                match storage.save().await {
+                    Ok(()) => print::info("Saved"),
+                    Err(err) => print::error(&format!("Unable to save: {}", err))
+                }
             },
             "end" => {
-                monikai.lock().await.end_conversation().await;
-                print::info("Ended Conversation");
+                match monikai.lock().await.end_conversation().await {
+                    Ok(id) => print::info(&format!("Ended Conversation, remembered as {}", id)),
+                    Err(err) => print::error(&format!("Unable to end the conversation: {}", err))
+                }
             },
             "log" => {
                 print::info("Logging");
 
-                let mut monikai_no_embeddings = monikai.lock().await.clone();
+                let monikai_no_embeddings = monikai.lock().await.without_embeddings();
+
+                print::debug(&serde_json::to_string_pretty(&monikai_no_embeddings).unwrap());
+            },
+            "profile" => {
+                let monikai = monikai.lock().await;
 
-                for memory in monikai_no_embeddings.memories.iter_mut() {
-                    memory.embedding = Vec::new();
+                if monikai.profile.facts.is_empty() {
+                    print::debug("Your Monikai hasn't learned anything about you yet!");
                 }
+                for fact in &monikai.profile.facts {
+                    let sources = fact.sources.iter()
+                        .map(|source| source.to_string())
+                        .collect::<Vec<String>>()
+                        .join(", ");
 
-                print::debug(&serde_json::to_string_pretty(&monikai_no_embeddings).unwrap());
+                    print::debug(&format!("{} (from {})", fact, sources));
+                }
+            },
+            "facts" => {
+                print::info("Please enter some text to search for, or nothing for every fact");
+                let Ok(Some(text)) = stdin.next_line().await else {
+                    break;
+                };
+
+                let monikai = monikai.lock().await;
+                let facts = monikai.knowledge.about(&text);
+
+                if facts.is_empty() {
+                    print::debug("Your Monikai doesn't know any facts like that!");
+                }
+                for fact in facts {
+                    let sources = if fact.set_by_hand() {
+                        String::from("set by hand")
+                    } else {
+                        format!("from {}", fact.sources.iter()
+                            .map(|source| source.to_string())
+                            .collect::<Vec<String>>()
+                            .join(", "))
+                    };
+
+                    print::debug(&format!("{}: {} ({:.2} sure, {})", fact.id, fact, fact.confidence, sources));
+                }
+            },
+            "fact" => {
+                print::info("Please enter the fact's ID");
+                let Ok(Some(id)) = stdin.next_line().await else {
+                    break;
+                };
+                let Ok(id) = id.trim().parse::<knowledge::FactId>() else {
+                    print::error(&format!("'{}' isn't a fact ID", id.trim()));
+                    continue;
+                };
+                print::info("Please enter what it should say as 'subject | predicate | object', or nothing to remove it");
+                let Ok(Some(replacement)) = stdin.next_line().await else {
+                    break;
+                };
+
+                let mut monikai = monikai.lock().await;
+                if replacement.trim().is_empty() {
+                    match monikai.unlearn(id) {
+                        Some(fact) => print::info(&format!("Removed '{}'", fact)),
+                        None => print::error(&format!("There's no fact {}", id))
+                    }
+                    continue;
+                }
+                let parts: Vec<&str> = replacement.split('|').map(str::trim).collect();
+                let [subject, predicate, object] = parts[..] else {
+                    print::error("A fact needs a subject, predicate and object, separated by '|'");
+                    continue;
+                };
+                if parts.iter().any(|part| part.is_empty()) {
+                    print::error("A fact needs a subject, predicate and object, separated by '|'");
+                    continue;
+                }
+                match monikai.edit_fact(id, subject, predicate, object) {
+                    Some(fact) => print::info(&format!("Now '{}'", fact)),
+                    None => print::error(&format!("There's no fact {}", id))
+                }
             },
             "get" => {
                 print::info("Please enter a key phrase to search by");
-                let mut keyword = String::new();
-                stdin.read_line(&mut keyword).unwrap();
-
-                let key_phrase_embedding = openai::embedding_request(&keyword).await.unwrap();
+                let Ok(Some(keyword)) = stdin.next_line().await else {
+                    break;
+                };
 
-                let mut memories_sorted: Vec<memory::Memory> = monikai.lock().await.memories
-                    .clone();
-                    
-                memories_sorted.sort_by(|a, b| {
-                        let a_sim = linalg::cosine_similarity(&key_phrase_embedding, &a.embedding);
-                        let b_sim = linalg::cosine_similarity(&key_phrase_embedding, &b.embedding);
+                let embedding_provider = monikai.lock().await.providers.embedding.clone();
+                let key_phrase_embedding = match embedding_provider.embed(&keyword).await {
+                    Ok(key_phrase_embedding) => key_phrase_embedding,
+                    Err(err) => {
+                        print::error(&format!("Unable to embed the key phrase: {}", err));
+                        continue;
+                    }
+                };
 
-                        a_sim.partial_cmp(&b_sim).unwrap()
-                    });
+                let mut monikai = monikai.lock().await;
+                let recalled = monikai.recall(&keyword, &key_phrase_embedding);
 
-                if let Some(most_similar) = memories_sorted.last() {
-                    print::debug(&format!("Most similar: {}", most_similar.conversation));
-                } else {
+                if monikai.memories.is_empty() {
                     print::debug("Your Monikai has no memories! Go make some :3");
+                } else if recalled.is_empty() {
+                    print::debug("No memories are related closely enough!");
+                }
+                for (index, score, chunk) in recalled {
+                    print::debug(&format!("Related ({:.4}) {}: {}", score, monikai.memories[index].id, monikai.memories[index].excerpt(chunk)));
+                }
+            }
+            "find" => {
+                print::info("Please enter some text to search for");
+                let Ok(Some(text)) = stdin.next_line().await else {
+                    break;
+                };
+
+                // Searches what's been saved, so save first to include anything since
+                if let Err(err) = storage.save().await {
+                    print::error(&format!("Unable to save before searching: {}", err));
+                }
+                match storage.search_memories(&text).await {
+                    Ok(memories) if memories.is_empty() => print::debug("No saved memories mention that!"),
+                    Ok(memories) => {
+                        for memory in memories {
+                            print::debug(&format!("{} from {} ago: {}", memory.id, memory.readable_time_since(), memory.conversation));
+                        }
+                    },
+                    Err(err) => print::error(&format!("Unable to search memories: {}", err))
+                }
+            }
+            "memory" | "forget" | "tag" => {
+                print::info("Please enter the memory's ID");
+                let Ok(Some(id)) = stdin.next_line().await else {
+                    break;
+                };
+                let Ok(id) = id.trim().parse::<memory::MemoryId>() else {
+                    print::error(&format!("'{}' isn't a memory ID", id.trim()));
+                    continue;
+                };
+
+                let memory = match buffer.as_str() {
+                    "forget" => monikai.lock().await.forget(id)
+                        .inspect(|_| print::info(&format!("Forgot {}", id))),
+                    "tag" => {
+                        print::info("Please enter the tags, separated by commas");
+                        let Ok(Some(tags)) = stdin.next_line().await else {
+                            break;
+                        };
+                        let tags: Vec<String> = tags.split(',')
+                            .map(|tag| tag.trim().to_string())
+                            .filter(|tag| !tag.is_empty())
+                            .collect();
+
+                        monikai.lock().await.tag(id, &tags).cloned()
+                    },
+                    _ => monikai.lock().await.memory(id).cloned()
+                };
+                match memory {
+                    Some(memory) => print::debug(&serde_json::to_string_pretty(&memory.without_embedding()).unwrap()),
+                    None => print::error(&format!("There's no memory {}", id))
                 }
             }
             _ => {
-                monikai.lock().await.send_message(buffer.clone()).await;
+                let (deltas, delta_receiver) = mpsc::unbounded_channel::<String>();
+                let printer = tokio::spawn(print_deltas(delta_receiver));
+
+                let result = monikai.lock().await.send_message(buffer, memory::Channel::Repl, Some(deltas)).await;
+                printer.await.ok();
+
+                if let Err(err) = result {
+                    print::error(&format!("Unable to respond: {}", err));
+                }
             }
         }
-    
-        buffer.clear();
+    }
+}
+// Prints a streamed response to the REPL as it arrives.
+async fn print_deltas( mut delta_receiver: mpsc::UnboundedReceiver<String> ) {
+    let mut started = false;
+
+    while let Some(delta) = delta_receiver.recv().await {
+        if !started {
+            print::monikai_delta(">");
+            started = true;
+        }
+
+        print::monikai_delta(&delta);
+    }
+
+    if started {
+        println!();
     }
 }
 /*
@@ -298,44 +800,204 @@ pub async fn monikai_repl( monikai: Arc<Mutex<Monikai>> ) {
  There is an example client in ../../public.
 
  For instance, given a response and context, the Monikai determines its visible emotion.
+
+ Fails if it can't bind to 'server.bind', such as when the port is taken.
 */
-pub async fn monikai_backend( monikai: Arc<Mutex<Monikai>>) {
-    let app = Router::new()
+pub async fn monikai_backend( monikai: Arc<Mutex<Monikai>>, server: ServerConfig, shutdown: Shutdown ) -> std::io::Result<()> {
+    let app = monikai_router( monikai, shutdown.clone() );
+
+    let listener = tokio::net::TcpListener::bind(&server.bind).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.wait().await })
+        .await
+}
+// Every route the backend serves.
+pub fn monikai_router( monikai: Arc<Mutex<Monikai>>, shutdown: Shutdown ) -> Router {
+    Router::new()
         .route("/", get(|| async { Html(std::include_str!("../../public/index.html")) }))
         .route("/ws", get(
-            |
+            move |
                 ws: WebSocketUpgrade,
                 axum::extract::State(state): axum::extract::State<Arc<Mutex<Monikai>>>,
-            | async {
+            | async move {
                 println!("Connection!");
-                ws.on_upgrade(|socket| monikai_websocket(socket, state))
+                ws.on_upgrade(move |socket| monikai_websocket(socket, state, shutdown))
             }
         ))
+        .route("/profile", get(get_profile))
+        .route("/memories", get(list_memories))
+        .route("/memories/:id", get(get_memory).delete(forget_memory))
+        .route("/memories/:id/tags", post(tag_memory))
+        .route("/facts", get(list_facts))
+        .route("/facts/:id", get(get_fact).put(edit_fact).delete(unlearn_fact))
         .nest_service("/public", ServeDir::new("public"))
-        .with_state(monikai);
-        
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .with_state(monikai)
+}
+/*
+ The REST API for memories, which are addressed by ID and sent without embeddings.
+
+  GET /memories lists every memory, oldest first.
+  GET /memories/<id> gets one.
+  DELETE /memories/<id> forgets one.
+  POST /memories/<id>/tags adds a JSON list of tags to one.
+
+ Along with the profile, and the facts:
+
+  GET /profile gets the profile.
+  GET /facts lists every fact, or with '?about=<text>', the ones mentioning it.
+  GET /facts/<id> gets one.
+  PUT /facts/<id> replaces what one says with a JSON '{"subject", "predicate", "object"}'.
+  DELETE /facts/<id> removes one.
+*/
+async fn get_profile( State(monikai): State<Arc<Mutex<Monikai>>> ) -> Json<profile::Profile> {
+    Json(monikai.lock().await.profile.clone())
+}
+async fn list_memories( State(monikai): State<Arc<Mutex<Monikai>>> ) -> Json<Vec<memory::Memory>> {
+    Json(monikai.lock().await.memories.iter()
+        .map(memory::Memory::without_embedding)
+        .collect())
+}
+async fn get_memory( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<memory::MemoryId> ) -> Result<Json<memory::Memory>, StatusCode> {
+    monikai.lock().await.memory(id)
+        .map(|memory| Json(memory.without_embedding()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+async fn forget_memory( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<memory::MemoryId> ) -> StatusCode {
+    match monikai.lock().await.forget(id) {
+        Some(_) => {
+            print::debug(&format!("Forgot memory {} (remote)", id));
+
+            StatusCode::NO_CONTENT
+        },
+        None => StatusCode::NOT_FOUND
+    }
 }
-// Helper function to preserve readability for the above backend.
-async fn monikai_websocket(stream: WebSocket, monikai: Arc<Mutex<Monikai>>) {
+async fn tag_memory( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<memory::MemoryId>, Json(tags): Json<Vec<String>> ) -> Result<Json<memory::Memory>, StatusCode> {
+    monikai.lock().await.tag(id, &tags)
+        .map(|memory| Json(memory.without_embedding()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+#[derive(Debug, Deserialize)]
+struct FactsQuery {
+    #[serde(default)]
+    about: String
+}
+#[derive(Debug, Deserialize)]
+struct FactEdit {
+    subject: String,
+    predicate: String,
+    object: String
+}
+async fn list_facts( State(monikai): State<Arc<Mutex<Monikai>>>, Query(query): Query<FactsQuery> ) -> Json<Vec<knowledge::Fact>> {
+    Json(monikai.lock().await.knowledge.about(&query.about)
+        .into_iter()
+        .cloned()
+        .collect())
+}
+async fn get_fact( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<knowledge::FactId> ) -> Result<Json<knowledge::Fact>, StatusCode> {
+    monikai.lock().await.knowledge.fact(id)
+        .map(|fact| Json(fact.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+async fn edit_fact( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<knowledge::FactId>, Json(edit): Json<FactEdit> ) -> Result<Json<knowledge::Fact>, StatusCode> {
+    if [&edit.subject, &edit.predicate, &edit.object].iter().any(|part| part.trim().is_empty()) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    monikai.lock().await.edit_fact(id, &edit.subject, &edit.predicate, &edit.object)
+        .map(|fact| Json(fact.clone()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+async fn unlearn_fact( State(monikai): State<Arc<Mutex<Monikai>>>, AxumPath(id): AxumPath<knowledge::FactId> ) -> StatusCode {
+    match monikai.lock().await.unlearn(id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND
+    }
+}
+const EMOTIONS: [&str; 5] = ["NEUTRAL", "SAD", "CRYING", "LAUGHING", "CONCERNED"];
+
+/*
+ Helper function to preserve readability for the above backend.
+
+ Protocol:
+  The client sends plain text messages.
+  While the response generates, '{"type": "delta", "delta": "..."}' is sent for each piece.
+  Once finished, '{"type": "message", "message": "...", "emotion": "..."}' carries the full response.
+  If anything fails, '{"type": "error", "error": "..."}' is sent instead, and the message can be resent.
+  When shutting down, the connection is closed with 1001 (going away), once any response in progress is sent.
+*/
+async fn monikai_websocket(stream: WebSocket, monikai: Arc<Mutex<Monikai>>, shutdown: Shutdown) {
     // By splitting, we can send and receive at the same time.
     let (mut sender, mut receiver) = stream.split();
 
     // Loop until a text message is found.
-    while let Some(Ok(message)) = receiver.next().await {
+    loop {
+        let message = tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(message)) => message,
+                _ => break
+            },
+            _ = shutdown.wait() => {
+                let close = axum::extract::ws::CloseFrame {
+                    code: axum::extract::ws::close_code::AWAY,
+                    reason: std::borrow::Cow::from("Shutting down")
+                };
+                sender.send(axum::extract::ws::Message::Close(Some(close))).await.ok();
+
+                break;
+            }
+        };
+
         if let axum::extract::ws::Message::Text(msg) = message {
             println!("(remote) {}", msg);
 
-            let response = monikai.lock().await.send_message(msg.clone()).await;
+            // Forward each piece of the response to the client as it arrives
+            let (deltas, mut delta_receiver) = mpsc::unbounded_channel::<String>();
+            let responding = async {
+                monikai.lock().await.send_message(msg.clone(), memory::Channel::Websocket, Some(deltas)).await
+            };
+            let forwarding = async {
+                let mut connected = true;
+
+                while let Some(delta) = delta_receiver.recv().await {
+                    let delta = serde_json::json!({ "type": "delta", "delta": delta }).to_string();
+
+                    if connected && sender.send(axum::extract::ws::Message::Text(delta)).await.is_err() {
+                        connected = false;
+                    }
+                }
+
+                connected
+            };
+            let (response, connected) = tokio::join!(responding, forwarding);
+            if !connected {
+                break;
+            }
+
+            let response = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    print::error(&format!("Unable to respond to remote: {}", err));
+
+                    let error = serde_json::json!({ "type": "error", "error": err.to_string() }).to_string();
+                    if sender.send(axum::extract::ws::Message::Text(error)).await.is_err() {
+                        break;
+                    }
+
+                    continue;
+                }
+            };
+            print::monikai(&response);
+
             let conversation = monikai.lock().await.current_conversation
                 .iter()
                 .map(|message| message.to_string())
                 .collect::<Vec<String>>()
                 .join("\n");
             let description = monikai.lock().await.description.clone();
+            let completion_provider = monikai.lock().await.providers.completion.clone();
 
-            let emotion = openai::instruction_request(format!("
+            let emotion = completion_provider.complete(format!("
                 {}
 
                 Based on the conversation, create an meotion (NEUTRAL | SAD | CRYING | LAUGHING | CONCERNED) that pairs well with your.
@@ -353,89 +1015,262 @@ async fn monikai_websocket(stream: WebSocket, monikai: Arc<Mutex<Monikai>>) {
                 {}
 
                 {{\"message\":\"{}\",\"emotion\":
-            ", description, conversation, response, response)).await.unwrap();
+            ", description, conversation, response, response)).await
+                .map(|emotion| {
+                    EMOTIONS.iter()
+                        .find(|candidate| emotion.contains(*candidate))
+                        .unwrap_or(&EMOTIONS[0])
+                        .to_string()
+                })
+                .unwrap_or_else(|err| {
+                    print::error(&format!("Unable to generate an emotion: {}", err));
+
+                    String::from(EMOTIONS[0])
+                });
 
-            let response_with_emotion = format!(r#"{{"message": "{}","emotion":{}"#, response, emotion);
+            let response_with_emotion = serde_json::json!({
+                "type": "message",
+                "message": response,
+                "emotion": emotion
+            }).to_string();
 
-            sender
+            if sender
                 .send(axum::extract::ws::Message::Text(response_with_emotion))
-                .await.unwrap();
+                .await.is_err()
+            {
+                break;
+            }
         }
     }
 }
 /**
- Automatically removes memories based on the ratio of time to access.
+ Automatically removes memories once they've been forgotten.
 
- Based on the Ebbinghaus Forgetting Curve (with slightly more aggressive constants)
- and Trace Decay Theory of Forgetting.
+ Based on the Ebbinghaus Forgetting Curve and Trace Decay Theory of Forgetting,
+ with each memory growing more stable as it's recalled (see 'memory::Memory::stability').
 
 
  During testing, I found that this created the most 'human-like' interactions, and that 
  limits had to be set, as the 'forgetting curve' isn't all inclusive, many people don't
- ever forget some memories, regardless of time. In Monikai's case, I quantified this as 50+
- recalls, a completely arbitrary number.
+ ever forget some memories, regardless of time. In Monikai's case, I quantified this as
+ having grown stable for over a year, a completely arbitrary number.
 **/
-pub async fn monikai_memory_agent( monikai: Arc<Mutex<Monikai>> ) {
+pub async fn monikai_memory_agent( monikai: Arc<Mutex<Monikai>>, timing: TimingConfig ) {
     loop {
-        let memories_clone = monikai.lock().await.memories.clone();
-        monikai.lock().await.memories = memories_clone
-            .iter()
-            .filter_map(|memory| {
-                let current_time = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-        
-                let days_since = current_time.saturating_sub( memory.timestamp ) / 86400;
+        // Pruned in place under one lock, so memories made meanwhile aren't lost and the order is kept
+        let current_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        monikai.lock().await.prune(current_time);
 
-                if days_since > 2 && memory.times_read < 50 {
-                    let forget_score: f64 = 1.15f64.powf(days_since as f64 - 6.99f64) / 1.15f64.powi(memory.times_read as i32) - 1f64;
-                    if forget_score < 1f64 {
-                        print::debug(&format!("Pruned memory: {}...", memory.interaction_summary.get(0..if memory.interaction_summary.len() < 35 { memory.interaction_summary.len() } else { 35 } ).unwrap()));
+        sleep(Duration::from_secs(timing.prune_interval_secs)).await;
+    }
+}
+// Resolves on Ctrl+C, or on SIGTERM where there is such a thing.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM!");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
+/*
+ Tells the REPL and backend to stop taking input, once triggered.
 
-                        return None;
-                    }
-                }
+ Clones share the same signal, and anything waiting on it after it's
+ been triggered returns straight away.
+*/
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { sender: Arc::new(watch::channel(false).0) }
+    }
+}
+impl Shutdown {
+    pub fn trigger( &self ) {
+        self.sender.send_replace(true);
+    }
+    pub async fn wait( &self ) {
+        self.sender.subscribe().wait_for(|triggered| *triggered).await.ok();
+    }
+}
+/*
+ Everything left to do once input has stopped: the open conversation
+ is made into a memory if 'end_conversation' is set, and then storage
+ is flushed and let go of.
 
-                Some(memory.clone())
-            })
-            .collect::<Vec<memory::Memory>>();
-        
+ A conversation that can't be made into a memory is saved as it is,
+ to be picked back up next time.
+*/
+pub async fn monikai_shutdown( monikai: Arc<Mutex<Monikai>>, storage: StorageHandle, end_conversation: bool ) {
+    if end_conversation {
+        let mut monikai = monikai.lock().await;
 
-        sleep(Duration::from_secs(15)).await;
+        if !monikai.current_conversation.is_empty() {
+            print::info("Making the conversation into a memory before exiting");
+
+            if let Err(err) = monikai.end_conversation().await {
+                print::error(&format!("Unable to end the conversation, it'll be kept for next time: {}", err));
+            }
+        }
     }
+
+    print::info("Saving before exiting");
+    storage.shutdown().await;
 }
 /*
- Automatically saves the Monikai every 5 seconds.
+ Owns the storage, and is the only task that writes to it.
+
+ Saves once the Monikai has changed and then gone 'save_debounce_ms'
+ without changing again, or at the latest 'autosave_secs' after the
+ first unsaved change. Saves the conversation as a memory after
+ 'conversation_timeout_secs' without being spoken to.
 
- Saves the conversation as a memory after 5 minutes.
+ A failed save is retried after a second, then twice as long after
+ each failure after that, up to 'autosave_secs'. Its error is only
+ printed the first time, until a save works again.
+
+ On a storage 'Shutdown' request, saves anything unsaved and returns.
 */
-pub async fn monikai_autosave( monikai: Arc<Mutex<Monikai>> ) {
+pub async fn monikai_autosave( monikai: Arc<Mutex<Monikai>>, mut storage: Box<dyn Storage>, timing: TimingConfig, mut requests: mpsc::UnboundedReceiver<StorageRequest> ) {
+    let debounce = Duration::from_millis(timing.save_debounce_ms);
+    let max_delay = Duration::from_secs(timing.autosave_secs);
+
+    // Whatever was loaded is already saved
+    let mut saved_version = monikai.lock().await.version;
+    let mut last_seen_version = saved_version;
+    let mut last_change = Instant::now();
+    let mut first_unsaved_change: Option<Instant> = None;
+    let mut last_conversation_check = Instant::now();
+    // How long to wait after the last failed save, and when that was
+    let mut retry: Option<(Duration, Instant)> = None;
+
+    let mut ticks = tokio::time::interval(debounce.clamp(Duration::from_millis(1), Duration::from_millis(100)));
+
+    let mut requests_open = true;
     loop {
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        tokio::select! {
+            _ = ticks.tick() => {},
+            request = requests.recv(), if requests_open => {
+                match request {
+                    Some(StorageRequest::Save(reply)) => {
+                        let snapshot = monikai.lock().await.clone();
+                        let version = snapshot.version;
+                        let result;
+                        (storage, result) = save_snapshot(storage, snapshot).await;
+                        if result.is_ok() {
+                            saved_version = version;
+                            first_unsaved_change = None;
+                            retry = None;
+                        }
+                        reply.send(result).ok();
+                    },
+                    Some(StorageRequest::Search(text, reply)) => {
+                        reply.send(storage.search_memories(&text)).ok();
+                    },
+                    Some(StorageRequest::Shutdown(reply)) => {
+                        let snapshot = monikai.lock().await.clone();
+                        if snapshot.version != saved_version {
+                            if let (_, Err(err)) = save_snapshot(storage, snapshot).await {
+                                print::error(&format!("Unable to save: {}", err));
+                            }
+                        }
+                        reply.send(()).ok();
+                        return;
+                    },
+                    // Nothing can ask for anything any more, but changes still need saving
+                    None => requests_open = false
+                }
+                continue;
+            }
+        }
 
-        let minutes_since = current_time.saturating_sub( monikai.lock().await.last_spoken_to ) / 60;
+        if last_conversation_check.elapsed() >= max_delay {
+            last_conversation_check = Instant::now();
 
-        let conversation_length = monikai.lock().await.current_conversation.len();
-        if minutes_since > 5 && conversation_length > 0 {
-            monikai.lock().await.end_conversation().await;
+            let current_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
 
-            print::debug("Ended conversation")
-        }
+            // Checked and ended under one lock, so it can't have already been ended by then
+            let mut monikai = monikai.lock().await;
+            let seconds_since = current_time.saturating_sub( monikai.last_spoken_to );
 
-        if let Ok(mut file_handle) = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open("data/monikai.json")
-        {
-            monikai.lock().await.save_to_file( &mut file_handle );
-        } else {
-            print::debug("File busy!");
+            if seconds_since > timing.conversation_timeout_secs && !monikai.current_conversation.is_empty() {
+                match monikai.end_conversation().await {
+                    Ok(id) => print::debug(&format!("Ended conversation, remembered as {}", id)),
+                    Err(err) => print::error(&format!("Unable to end the conversation, will retry: {}", err))
+                }
+            }
         }
 
-        sleep(Duration::from_secs(5)).await;
+        let snapshot = {
+            let monikai = monikai.lock().await;
+            if monikai.version != last_seen_version {
+                last_seen_version = monikai.version;
+                last_change = Instant::now();
+                first_unsaved_change.get_or_insert(last_change);
+            }
+
+            let Some(first_unsaved) = first_unsaved_change else {
+                continue;
+            };
+            if last_change.elapsed() < debounce && first_unsaved.elapsed() < max_delay {
+                continue;
+            }
+            if retry.is_some_and(|(backoff, failed_at)| failed_at.elapsed() < backoff) {
+                continue;
+            }
+
+            monikai.clone()
+        };
+
+        let version = snapshot.version;
+        let result;
+        (storage, result) = save_snapshot(storage, snapshot).await;
+        match result {
+            Ok(()) => {
+                if retry.take().is_some() {
+                    print::info("Saved again");
+                }
+                saved_version = version;
+                first_unsaved_change = None;
+            },
+            Err(err) => {
+                let backoff = match retry {
+                    Some((backoff, _)) => (backoff * 2).min(max_delay),
+                    None => {
+                        print::error(&format!("Unable to save, will retry: {}", err));
+                        Duration::from_secs(1).min(max_delay)
+                    }
+                };
+                retry = Some((backoff, Instant::now()));
+            }
+        }
     }
-}
\ No newline at end of file
+}
+/*
+ Saves a copy of the Monikai on a blocking thread, since saving waits on the
+ disk, so neither the runtime nor the Monikai's lock are held up by it.
+
+ The storage is handed back along with how the save went.
+*/
+async fn save_snapshot( mut storage: Box<dyn Storage>, snapshot: Monikai ) -> (Box<dyn Storage>, Result<(), StorageError>) {
+    tokio::task::spawn_blocking(move || {
+        let result = storage.save(&snapshot);
+
+        (storage, result)
+    }).await.expect("Saving panicked")
+}
@@ -1,21 +1,311 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{ BuildHasher, Hasher };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use reqwest::{ header::HeaderMap, StatusCode };
+use serde::de::DeserializeOwned;
+use tokio::sync::{ mpsc, OwnedSemaphorePermit, Semaphore };
+use tokio::time::sleep;
+
 use crate::{ Serialize, Deserialize };
-use crate::env;
+use crate::config::{ OpenAiConfig, PromptConfig };
+use crate::llm::{ self, Message };
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/*
+ The default provider, backed by the OpenAI REST API.
+
+ The base URL can be pointed at anything that speaks the same protocol,
+ such as a local proxy or the mock server used by the tests.
+
+ An API key is only required by the official API, local servers
+ generally don't check for one.
+
+ Clones share the same rate limiter, so one client can safely be
+ used for every role at once.
+*/
+#[derive(Debug, Clone)]
+pub struct OpenAi {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub chat_model: String,
+    pub completion_model: String,
+    pub embedding_model: String,
+    // The longest completion to ask for
+    pub max_tokens: usize,
+    // The longest chat response to ask for
+    pub response_tokens: usize,
+    pub temperature: f32,
+    pub retry_policy: RetryPolicy,
+    client: reqwest::Client,
+    limiter: Arc<RateLimiter>
+}
+
+/*
+ How failed requests are retried.
+
+ Rate limits (429), server errors (5xx) and dropped connections are
+ retried with exponential backoff and jitter, unless the server says
+ how long to wait with 'Retry-After'. Either way, no retry waits
+ longer than 'max_delay'.
+*/
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration
+}
+impl RetryPolicy {
+    fn delay( &self, attempt: u32, err: &llm::Error ) -> Duration {
+        if let llm::Error::RateLimited { retry_after: Some(retry_after) } = err {
+            return (*retry_after).min(self.max_delay);
+        }
+
+        let backoff = self.base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        // Jitter between half and all of the backoff, so parallel callers spread out
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        backoff / 2 + (backoff / 2).mul_f64(jitter as f64 / 1000f64)
+    }
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30)
+        }
+    }
+}
+
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 2;
+
+/*
+ Limits how many requests are in flight at once, and holds back new
+ requests once the 'x-ratelimit-*' headers report nothing remaining.
+*/
+#[derive(Debug)]
+struct RateLimiter {
+    permits: Arc<Semaphore>,
+    blocked_until: std::sync::Mutex<Option<Instant>>
+}
+impl RateLimiter {
+    fn new( max_concurrent_requests: usize ) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent_requests.max(1))),
+            blocked_until: std::sync::Mutex::new(None)
+        }
+    }
+    async fn wait( &self ) {
+        let blocked_until = *self.blocked_until.lock().unwrap();
+
+        if let Some(blocked_until) = blocked_until {
+            let now = Instant::now();
+            if blocked_until > now {
+                sleep(blocked_until - now).await;
+            }
+        }
+    }
+    fn block_for( &self, duration: Duration ) {
+        let until = Instant::now() + duration;
+        let mut blocked_until = self.blocked_until.lock().unwrap();
+
+        if blocked_until.is_none_or(|blocked_until| blocked_until < until) {
+            *blocked_until = Some(until);
+        }
+    }
+    fn observe( &self, headers: &HeaderMap ) {
+        for kind in ["requests", "tokens"] {
+            let remaining = header(headers, &format!("x-ratelimit-remaining-{}", kind))
+                .and_then(|remaining| remaining.trim().parse::<u64>().ok());
+            let reset = header(headers, &format!("x-ratelimit-reset-{}", kind))
+                .and_then(parse_reset_duration);
+
+            if let (Some(0), Some(reset)) = (remaining, reset) {
+                self.block_for(reset);
+            }
+        }
+    }
+}
+
+// Parses OpenAI's reset durations, such as '20ms', '1s' or '6m0.5s'.
+fn parse_reset_duration( input: &str ) -> Option<Duration> {
+    let mut total = 0f64;
+    let mut number = String::new();
+    let mut chars = input.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let value = number.parse::<f64>().ok()?;
+        number.clear();
+
+        total += match c {
+            'h' => value * 3600f64,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                value / 1000f64
+            },
+            'm' => value * 60f64,
+            's' => value,
+            _ => return None
+        };
+    }
+
+    if !number.is_empty() {
+        total += number.parse::<f64>().ok()?;
+    }
+
+    Some(Duration::from_secs_f64(total))
+}
+
+fn parse_retry_after( headers: &HeaderMap ) -> Option<Duration> {
+    if let Some(retry_after_ms) = header(headers, "retry-after-ms")
+        .and_then(|retry_after_ms| retry_after_ms.trim().parse::<u64>().ok())
+    {
+        return Some(Duration::from_millis(retry_after_ms));
+    }
+
+    header(headers, "retry-after")
+        .and_then(|retry_after| retry_after.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+fn header<'a>( headers: &'a HeaderMap, name: &str ) -> Option<&'a str> {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+impl OpenAi {
+    pub fn from_config( config: &OpenAiConfig ) -> Self {
+        Self {
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            api_key: config.api_key.clone().filter(|api_key| !api_key.is_empty()),
+            chat_model: config.chat_model.clone(),
+            completion_model: config.completion_model.clone(),
+            embedding_model: config.embedding_model.clone(),
+            max_tokens: config.max_tokens,
+            response_tokens: PromptConfig::default().response_tokens,
+            temperature: config.temperature,
+            retry_policy: RetryPolicy {
+                max_retries: config.max_retries,
+                ..RetryPolicy::default()
+            },
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(10))
+                .read_timeout(Duration::from_secs(120))
+                .build()
+                .expect("Unable to build the HTTP client!"),
+            limiter: Arc::new(RateLimiter::new(config.max_concurrent_requests))
+        }
+    }
+    // Limits chat responses to what the prompt's budget keeps room for, see 'PromptConfig'.
+    pub fn with_response_tokens( mut self, response_tokens: usize ) -> Self {
+        self.response_tokens = response_tokens;
+        self
+    }
+    fn endpoint( &self, path: &str ) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+    async fn post<T: DeserializeOwned>( &self, path: &str, body: &(impl Serialize + Sync) ) -> Result<T, llm::Error> {
+        let (response, _permit) = self.send(path, body).await?;
+
+        let response_string = response.text().await
+            .map_err(|err| llm::Error::Transport(err.to_string()))?;
+
+        serde_json::from_str(&response_string)
+            .map_err(|err| llm::Error::MalformedResponse(err.to_string()))
+    }
+    /*
+     Sends a request, retrying until it is accepted or the retry policy gives up.
+
+     The returned permit counts against the concurrency limit until it is
+     dropped, so streamed responses should hold it until they finish.
+    */
+    async fn send( &self, path: &str, body: &(impl Serialize + Sync) ) -> Result<(reqwest::Response, OwnedSemaphorePermit), llm::Error> {
+        let mut attempt = 0;
+
+        loop {
+            let err = match self.try_send(path, body).await {
+                Ok(response) => return Ok(response),
+                Err(err) => err
+            };
+
+            let retryable = match &err {
+                llm::Error::RateLimited { .. } | llm::Error::Transport(_) => true,
+                llm::Error::Http { status, .. } => *status >= 500,
+                _ => false
+            };
+            if !retryable || attempt >= self.retry_policy.max_retries {
+                return Err(err);
+            }
+
+            let delay = self.retry_policy.delay(attempt, &err);
+            if matches!(err, llm::Error::RateLimited { .. }) {
+                self.limiter.block_for(delay);
+            }
+
+            attempt += 1;
+            crate::print::debug(&format!("{}, retrying ({}/{}) in {}ms", err, attempt, self.retry_policy.max_retries, delay.as_millis()));
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Message {
-    pub role: String,
-    pub content: String
+            sleep(delay).await;
+        }
+    }
+    async fn try_send( &self, path: &str, body: &(impl Serialize + Sync) ) -> Result<(reqwest::Response, OwnedSemaphorePermit), llm::Error> {
+        let mut request = self.client.post(self.endpoint(path))
+            .json(body);
+
+        match &self.api_key {
+            Some(api_key) => {
+                request = request.bearer_auth(api_key);
+            },
+            None if self.base_url == DEFAULT_BASE_URL => return Err(llm::Error::MissingApiKey),
+            None => {}
+        }
+
+        self.limiter.wait().await;
+        let permit = self.limiter.permits.clone().acquire_owned().await
+            .expect("The rate limiter is never closed");
+
+        let response = request.send().await
+            .map_err(|err| llm::Error::Transport(err.to_string()))?;
+        self.limiter.observe(response.headers());
+
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(llm::Error::RateLimited { retry_after: parse_retry_after(response.headers()) });
+        }
+        if !status.is_success() {
+            return Err(llm::Error::Http {
+                status: status.as_u16(),
+                body: response.text().await.unwrap_or_default()
+            });
+        }
+
+        Ok((response, permit))
+    }
 }
-impl Message {
-    pub fn to_string( &self ) -> String {
-        format!("{}: {}", self.role, self.content)
+impl Default for OpenAi {
+    fn default() -> Self {
+        Self::from_config(&OpenAiConfig::default())
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CompletionRequest {
     model: String,
-    messages: Vec<Message>
+    messages: Vec<Message>,
+    max_tokens: usize,
+    temperature: f32,
+    stream: bool
 }
 #[derive(Debug, Deserialize)]
 struct CompletionResponse {
@@ -25,22 +315,98 @@ struct CompletionResponse {
 struct CompletionChoice {
     message: Message
 }
+#[derive(Debug, Deserialize)]
+struct CompletionChunk {
+    choices: Vec<CompletionChunkChoice>
+}
+#[derive(Debug, Deserialize)]
+struct CompletionChunkChoice {
+    delta: CompletionDelta
+}
+#[derive(Debug, Deserialize)]
+struct CompletionDelta {
+    role: Option<String>,
+    content: Option<String>
+}
+
+#[async_trait]
+impl llm::ChatProvider for OpenAi {
+    async fn chat( &self, messages: Vec<Message> ) -> Result<Message, llm::Error> {
+        let completion_request = CompletionRequest {
+            model: self.chat_model.clone(),
+            messages,
+            max_tokens: self.response_tokens,
+            temperature: self.temperature,
+            stream: false
+        };
+
+        let completion_response: CompletionResponse = self.post("chat/completions", &completion_request).await?;
+
+        completion_response.choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or(llm::Error::EmptyChoices)
+    }
+    fn model( &self ) -> &str {
+        &self.chat_model
+    }
+    /*
+     Streams the response as server-sent events, where each event
+     carries the next delta of the message until '[DONE]'.
+    */
+    async fn chat_stream( &self, messages: Vec<Message>, deltas: mpsc::UnboundedSender<String> ) -> Result<Message, llm::Error> {
+        let completion_request = CompletionRequest {
+            model: self.chat_model.clone(),
+            messages,
+            max_tokens: self.response_tokens,
+            temperature: self.temperature,
+            stream: true
+        };
+
+        let (response, _permit) = self.send("chat/completions", &completion_request).await?;
+        let mut body = response.bytes_stream();
+
+        let mut message = Message { role: String::from("assistant"), content: String::new() };
+        let mut received_any = false;
+        let mut buffer: Vec<u8> = Vec::new();
+
+        while let Some(bytes) = body.next().await {
+            let bytes = bytes.map_err(|err| llm::Error::Transport(err.to_string()))?;
+            buffer.extend_from_slice(&bytes);
 
-pub async fn turbo_request( messages: Vec<Message> ) -> Result<Message, ()> {
-    let completion_request = CompletionRequest {
-        model: String::from("gpt-3.5-turbo"),
-        messages
-    };
-    
-    let request: String = ureq::post("https://api.openai.com/v1/chat/completions")
-        .set("Authorization", &format!("Bearer {}", env::var("OPENAI_API_KEY").unwrap()))
-        .set("Content-Type", "application/json")
-        .send_string(&serde_json::to_string(&completion_request).unwrap()).unwrap()
-        .into_string().unwrap();
+            // Only handle complete lines, since events can be split across chunks
+            while let Some(line_end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=line_end).collect();
+                let line = String::from_utf8_lossy(&line);
 
-    let deserialized_completion_response: CompletionResponse = serde_json::from_str(&request).unwrap();
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    return if received_any { Ok(message) } else { Err(llm::Error::EmptyChoices) };
+                }
 
-    Ok(deserialized_completion_response.choices[0].message.clone())
+                let chunk: CompletionChunk = serde_json::from_str(data)
+                    .map_err(|err| llm::Error::MalformedResponse(err.to_string()))?;
+
+                for choice in chunk.choices {
+                    received_any = true;
+
+                    if let Some(role) = choice.delta.role {
+                        message.role = role;
+                    }
+                    if let Some(content) = choice.delta.content.filter(|content| !content.is_empty()) {
+                        message.content.push_str(&content);
+                        deltas.send(content).ok();
+                    }
+                }
+            }
+        }
+
+        Err(llm::Error::MalformedResponse(String::from("The stream ended before '[DONE]'")))
+    }
 }
 
 
@@ -60,23 +426,30 @@ struct InstructionChoice {
     text: String
 }
 
-pub async fn instruction_request( prompt: String ) -> Result<String, ()> {
-    let instruct_request = serde_json::to_string(&InstructionRequest {
-        model: String::from("gpt-3.5-turbo-instruct-0914"),
-        prompt,
-        temperature: 1.,
-        max_tokens: 256
-    }).unwrap();
-    
-    let request: String = ureq::post("https://api.openai.com/v1/completions")
-        .set("Authorization", &format!("Bearer {}", env::var("OPENAI_API_KEY").unwrap()))
-        .set("Content-Type", "application/json")
-        .send_string(&instruct_request).unwrap()
-        .into_string().unwrap();
+#[async_trait]
+impl llm::CompletionProvider for OpenAi {
+    async fn complete( &self, prompt: String ) -> Result<String, llm::Error> {
+        let instruct_request = InstructionRequest {
+            model: self.completion_model.clone(),
+            prompt,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens
+        };
 
-    let deserialized_instruction_response: InstructionResponse = serde_json::from_str(&request).unwrap();
+        let instruction_response: InstructionResponse = self.post("completions", &instruct_request).await?;
 
-    Ok(deserialized_instruction_response.choices[0].text.clone())
+        instruction_response.choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.text)
+            .ok_or(llm::Error::EmptyChoices)
+    }
+    fn model( &self ) -> &str {
+        &self.completion_model
+    }
+    fn max_tokens( &self ) -> usize {
+        self.max_tokens
+    }
 }
 
 
@@ -94,19 +467,23 @@ struct EmbeddingData {
     embedding: Vec<f64>
 }
 
-pub async fn embedding_request( input: &String ) -> Result<Vec<f64>, ()> {
-    let embed_request = serde_json::to_string(&EmbeddingRequest {
-        model: String::from("text-embedding-ada-002"),
-        input: input.clone()
-    }).unwrap();
-    
-    let request: String = ureq::post("https://api.openai.com/v1/embeddings")
-        .set("Authorization", &format!("Bearer {}", env::var("OPENAI_API_KEY").unwrap()))
-        .set("Content-Type", "application/json")
-        .send_string(&embed_request).unwrap()
-        .into_string().unwrap();
+#[async_trait]
+impl llm::EmbeddingProvider for OpenAi {
+    async fn embed( &self, input: &str ) -> Result<Vec<f64>, llm::Error> {
+        let embed_request = EmbeddingRequest {
+            model: self.embedding_model.clone(),
+            input: input.to_string()
+        };
 
-    let deserialized_embedding_response: EmbeddingResponse = serde_json::from_str(&request).unwrap();
+        let embedding_response: EmbeddingResponse = self.post("embeddings", &embed_request).await?;
 
-    Ok(deserialized_embedding_response.data[0].embedding.clone())
-}
\ No newline at end of file
+        embedding_response.data
+            .into_iter()
+            .next()
+            .map(|data| data.embedding)
+            .ok_or(llm::Error::EmptyChoices)
+    }
+    fn model( &self ) -> &str {
+        &self.embedding_model
+    }
+}
@@ -1,3 +1,4 @@
+use std::io::Write;
 use colorama::Colored;
 
 pub fn info( input: &str ) {
@@ -8,6 +9,16 @@ pub fn monikai( input: &str ) {
     println!(">{}", String::from(input).color("bright magenta") );
 }
 
+// Prints part of a streamed response, without ending the line.
+pub fn monikai_delta( input: &str ) {
+    print!("{}", String::from(input).color("bright magenta") );
+    std::io::stdout().flush().ok();
+}
+
 pub fn debug( input: &str ) {
     println!("{}", format!("[{}]", input).color("green") );
+}
+
+pub fn error( input: &str ) {
+    eprintln!("{}", format!("[{}]", input).color("red") );
 }
\ No newline at end of file
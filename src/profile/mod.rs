@@ -0,0 +1,215 @@
+use std::fmt;
+
+use crate::{ Serialize, Deserialize };
+use crate::memory::{ Memory, MemoryId };
+
+// How many of the newest memories' profiles an older character's profile is started from
+pub const LEGACY_PROFILES: usize = 20;
+
+/*
+ What the Monikai knows about the MC, consolidated from every conversation
+ rather than kept per memory.
+
+ Each fact notes the memories it was learned from, and when it was last
+ learned or confirmed, which is what decides contradictions.
+ Facts outlive the memories they came from being forgotten, but not
+ the memories being removed on purpose (see 'Monikai::forget').
+*/
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Profile {
+    pub facts: Vec<Fact>
+}
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Fact {
+    pub category: Category,
+    pub text: String,
+    pub sources: Vec<MemoryId>,
+    // In seconds since the Unix epoch
+    pub updated: u64
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Like,
+    Dislike,
+    Person,
+    Plan,
+    #[serde(other)]
+    Other
+}
+impl fmt::Display for Category {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            Category::Like => write!(f, "like"),
+            Category::Dislike => write!(f, "dislike"),
+            Category::Person => write!(f, "person"),
+            Category::Plan => write!(f, "plan"),
+            Category::Other => write!(f, "other")
+        }
+    }
+}
+impl fmt::Display for Fact {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(f, "({}) {}", self.category, self.text)
+    }
+}
+
+/*
+ A new or changed fact as the model gives it back when merging, with the
+ numbers of the facts it replaces, and whether the new memory told it.
+*/
+#[derive(Debug, Deserialize)]
+struct MergedFact {
+    category: Category,
+    fact: String,
+    #[serde(default)]
+    from: Vec<usize>,
+    #[serde(default)]
+    new: bool
+}
+
+impl Profile {
+    /*
+     A profile for a character from before they were consolidated, with
+     each of the newest memories' profiles as a fact of its own, to be
+     merged properly with the next conversation.
+    */
+    pub fn from_memories<'a>( memories: impl DoubleEndedIterator<Item = &'a Memory> ) -> Self {
+        let mut facts: Vec<Fact> = Vec::new();
+        for memory in memories.rev() {
+            if facts.len() == LEGACY_PROFILES {
+                break;
+            }
+            if memory.user_profile.trim().is_empty() || facts.iter().any(|fact| fact.text == memory.user_profile.trim()) {
+                continue;
+            }
+
+            facts.push(Fact {
+                category: Category::Other,
+                text: memory.user_profile.trim().to_string(),
+                sources: vec!(memory.id),
+                updated: memory.timestamp
+            });
+        }
+        facts.reverse();
+
+        Self { facts }
+    }
+    // The facts, oldest first, one per line.
+    pub fn lines( &self ) -> impl DoubleEndedIterator<Item = String> + '_ {
+        let mut facts: Vec<&Fact> = self.facts.iter().collect();
+        facts.sort_by_key(|fact| fact.updated);
+
+        facts.into_iter().map(|fact| format!("- {}", fact))
+    }
+    /*
+     The prompt asking the model to merge what was learned from 'memory'
+     into the profile.
+
+     Facts are numbered from 1, for the model to say which of them each
+     of its facts replaces.
+    */
+    pub fn merge_prompt( &self, memory: &Memory ) -> String {
+        let facts = self.facts.iter()
+            .enumerate()
+            .map(|(number, fact)| format!("{}. {}", number + 1, fact))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        format!("
+            You are Monikai, keeping a PROFILE of what you know about the MC.
+            Merge what you learned from the NEW CONVERSATION into it.
+
+            Keep one fact per item, each a like, dislike, person, plan or other, and leave out anything not about the MC.
+            The new conversation is the most recent, so where it contradicts a fact, it is right.
+
+            Respond with only the facts that are new or changed, as a JSON list, where 'from' is the numbers
+            of the facts each replaces or combines, and 'new' is whether the new conversation told you it.
+            Facts you don't replace are kept as they are. For example:
+            [{{\"category\": \"like\", \"fact\": \"The MC loves science fiction and fantasy.\", \"from\": [1, 4], \"new\": true}}]
+
+            PROFILE:
+            {}
+
+            NEW CONVERSATION:
+            {}
+            {}
+
+            NEW OR CHANGED FACTS:
+
+        ", facts, memory.user_profile, memory.interaction_summary)
+    }
+    /*
+     The profile with the model's answer to 'merge_prompt' applied,
+     or None if the answer can't be read.
+
+     New facts replace whichever they were made from, keeping their
+     sources, and take the memory as a source too if it told them.
+     Facts with the same text are combined.
+    */
+    pub fn merged( &self, answer: &str, memory: &Memory ) -> Option<Self> {
+        let start = answer.find('[')?;
+        let end = answer.rfind(']')?;
+        let merged_facts: Vec<MergedFact> = serde_json::from_str(answer.get(start..=end)?).ok()?;
+
+        let replaced: Vec<usize> = merged_facts.iter()
+            .flat_map(|merged_fact| merged_fact.from.iter())
+            .filter_map(|number| number.checked_sub(1))
+            .collect();
+        let mut facts: Vec<Fact> = self.facts.iter()
+            .enumerate()
+            .filter(|(index, _)| !replaced.contains(index))
+            .map(|(_, fact)| fact.clone())
+            .collect();
+        for merged_fact in merged_facts {
+            let text = merged_fact.fact.trim().to_string();
+            if text.is_empty() {
+                continue;
+            }
+
+            let sources_of: Vec<&Fact> = merged_fact.from.iter()
+                .filter_map(|number| number.checked_sub(1).and_then(|index| self.facts.get(index)))
+                .collect();
+            let mut fact = Fact {
+                category: merged_fact.category,
+                text,
+                sources: Vec::new(),
+                updated: sources_of.iter().map(|fact| fact.updated).max().unwrap_or(memory.timestamp)
+            };
+            for source in sources_of.iter().flat_map(|fact| fact.sources.iter()) {
+                if !fact.sources.contains(source) {
+                    fact.sources.push(*source);
+                }
+            }
+            // Anything not made from an older fact can only have come from the memory
+            if merged_fact.new || sources_of.is_empty() {
+                if !fact.sources.contains(&memory.id) {
+                    fact.sources.push(memory.id);
+                }
+                fact.updated = memory.timestamp;
+            }
+
+            match facts.iter_mut().find(|existing| existing.category == fact.category && existing.text.eq_ignore_ascii_case(&fact.text)) {
+                Some(existing) => {
+                    for source in fact.sources {
+                        if !existing.sources.contains(&source) {
+                            existing.sources.push(source);
+                        }
+                    }
+                    existing.updated = existing.updated.max(fact.updated);
+                },
+                None => facts.push(fact)
+            }
+        }
+
+        Some(Self { facts })
+    }
+    // Stops crediting a memory with any of the profile, removing whatever was only learned from it.
+    pub fn forget( &mut self, id: MemoryId ) {
+        for fact in self.facts.iter_mut() {
+            fact.sources.retain(|source| *source != id);
+        }
+        self.facts.retain(|fact| !fact.sources.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use tiktoken_rs::{ tokenizer, CoreBPE };
+
+use crate::config::PromptConfig;
+use crate::llm;
+
+// What each chat message costs on top of its role and content, for the delimiters around them
+const TOKENS_PER_MESSAGE: usize = 4;
+// What the reply is primed with
+const REPLY_PRIMING_TOKENS: usize = 3;
+// Around the description, profile, memories and summary, counting their role
+const SYSTEM_MESSAGE_TOKENS: usize = 4 * (TOKENS_PER_MESSAGE + 1);
+
+/*
+ Counts tokens the way a chat model does.
+
+ Models tiktoken doesn't know (local ones, say) are counted as
+ cl100k_base, which is close enough for budgeting.
+*/
+#[derive(Clone, Copy)]
+pub struct Tokenizer {
+    bpe: &'static CoreBPE
+}
+impl Tokenizer {
+    pub fn for_model( model: &str ) -> Self {
+        let bpe = match tokenizer::get_tokenizer(model) {
+            Some(tokenizer::Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+            Some(tokenizer::Tokenizer::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+            Some(tokenizer::Tokenizer::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+            Some(tokenizer::Tokenizer::R50kBase | tokenizer::Tokenizer::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+            Some(tokenizer::Tokenizer::Cl100kBase) | None => tiktoken_rs::cl100k_base_singleton()
+        };
+
+        Self { bpe }
+    }
+    pub fn count( &self, text: &str ) -> usize {
+        self.bpe.encode_with_special_tokens(text).len()
+    }
+    pub fn count_message( &self, message: &llm::Message ) -> usize {
+        TOKENS_PER_MESSAGE + self.count(&message.role) + self.count(&message.content)
+    }
+    // The end of the text, cut down to at most 'tokens'.
+    pub fn tail( &self, text: &str, tokens: usize ) -> String {
+        let encoded = self.bpe.encode_with_special_tokens(text);
+        if encoded.len() <= tokens {
+            return text.to_string();
+        }
+
+        // Likewise, move the cut forward until it's between characters
+        (encoded.len() - tokens..=encoded.len())
+            .find_map(|start| self.bpe.decode(encoded[start..].to_vec()).ok())
+            .unwrap_or_default()
+    }
+    // The start of the text, cut down to at most 'tokens'.
+    pub fn truncate( &self, text: &str, tokens: usize ) -> String {
+        let encoded = self.bpe.encode_with_special_tokens(text);
+        if encoded.len() <= tokens {
+            return text.to_string();
+        }
+
+        // A cut can land inside a character, so back off until it doesn't
+        (0..=tokens).rev()
+            .find_map(|length| self.bpe.decode(encoded[..length].to_vec()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/*
+ How many tokens a model can take at once.
+
+ tiktoken takes the instruct models for their chat namesakes, which have
+ a larger context, so they're given the 4096 they actually have.
+*/
+pub fn context_size( model: &str ) -> usize {
+    if model.contains("instruct") {
+        return 4096;
+    }
+
+    tiktoken_rs::model::get_context_size(model)
+}
+
+/*
+ How many tokens each part of the prompt may take.
+
+ The description comes first, and may take up to half of whatever the
+ response leaves. Of the rest, the user profile and recalled memories
+ are given their shares, and the conversation everything they don't use.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub description: usize,
+    pub profile: usize,
+    pub memories: usize,
+    // Before whatever the profile and memories leave unused
+    pub conversation: usize
+}
+impl Budget {
+    pub fn allocate( config: &PromptConfig, chat_model: &str, description_tokens: usize ) -> Self {
+        let context_tokens = config.context_tokens
+            .unwrap_or_else(|| context_size(chat_model));
+        let available = context_tokens.saturating_sub(config.response_tokens + REPLY_PRIMING_TOKENS + SYSTEM_MESSAGE_TOKENS);
+
+        let description = description_tokens.min(available / 2);
+        let rest = available - description;
+        let profile = (rest as f64 * config.profile_share) as usize;
+        let memories = (rest as f64 * config.memory_share) as usize;
+
+        Self {
+            description,
+            profile,
+            memories,
+            conversation: rest.saturating_sub(profile + memories)
+        }
+    }
+}
+
+// Joins as many of the texts as fit, preferring the last ones, but keeping them in order.
+pub fn fit_latest<'a>( texts: impl DoubleEndedIterator<Item = &'a str>, budget: usize, tokenizer: &Tokenizer ) -> String {
+    let mut used = 0;
+    let mut fitting: Vec<&str> = Vec::new();
+    for text in texts.rev() {
+        let tokens = tokenizer.count(text) + 1;
+        if used + tokens > budget {
+            break;
+        }
+
+        used += tokens;
+        fitting.push(text);
+    }
+    fitting.reverse();
+
+    fitting.join("\n")
+}
+/*
+ Where the latest turns that fit start.
+
+ The very last turn is always kept, even if it doesn't fit by itself,
+ so there's always something to respond to.
+*/
+pub fn first_fitting( turns: &[llm::Message], budget: usize, tokenizer: &Tokenizer ) -> usize {
+    let mut used = 0;
+    for (position, turn) in turns.iter().enumerate().rev() {
+        used += tokenizer.count_message(turn);
+
+        if used > budget {
+            return (position + 1).min(turns.len().saturating_sub(1));
+        }
+    }
+
+    0
+}
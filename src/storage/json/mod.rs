@@ -0,0 +1,255 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use crate::memory;
+use crate::print;
+use crate::monikai::Monikai;
+use super::{ migrations, Storage, StorageError };
+
+/*
+ The whole character as one JSON file, rewritten on every save.
+
+ Saves go to a temporary file which then replaces the real one, so a crash
+ part way through leaves the last save intact rather than an empty file.
+
+ The memory index is kept next to it as '<name>.index.json',
+ and only rewritten when it changes.
+
+ With backups enabled, a copy of a save is also kept in 'backups/' as
+ '<name>-<milliseconds>.json' every so often, keeping only the newest few.
+
+ Older files are migrated as they're read. Once migrated for good,
+ the file from before is kept in 'backups/' as '<name>-schema-<version>.json'.
+*/
+pub struct JsonStorage {
+    path: PathBuf,
+    saved_index_generation: Option<u64>,
+    backups: usize,
+    backup_interval: Duration,
+    last_backup: Option<Instant>
+}
+
+// Writes to a temporary file first, so the file is only ever the old or the new contents.
+fn write_atomically( path: &Path, contents: &[u8] ) -> std::io::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temporary_path, path)?;
+
+    // The rename itself only survives a power loss once the directory is synced
+    #[cfg(unix)]
+    {
+        let directory = match path.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory,
+            _ => Path::new(".")
+        };
+        File::open(directory)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+// Reads a character saved with any schema up to the current one.
+fn parse_character( character_json_string: &str, location: &Path ) -> Result<Monikai, StorageError> {
+    let mut character: serde_json::Value = serde_json::from_str(character_json_string)
+        .map_err(|err| StorageError::Parse(format!("'{}': {}", location.display(), err)))?;
+    migrations::migrate(&mut character)
+        .map_err(|err| StorageError::Schema(format!("'{}': {}", location.display(), err)))?;
+
+    let mut monikai = serde_json::from_value::<Monikai>(character)
+        .map_err(|err| StorageError::Parse(format!("'{}': {}", location.display(), err)))?;
+    monikai.sort_memories();
+
+    Ok(monikai)
+}
+
+impl JsonStorage {
+    pub fn new( path: &Path ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            saved_index_generation: None,
+            backups: 0,
+            backup_interval: Duration::ZERO,
+            last_backup: None
+        }
+    }
+    // Keeps up to 'backups' backups, taken at most once every 'interval'.
+    pub fn with_backups( mut self, backups: usize, interval: Duration ) -> Self {
+        self.backups = backups;
+        self.backup_interval = interval;
+        self
+    }
+    fn backup_directory( &self ) -> PathBuf {
+        self.path.parent()
+            .unwrap_or(Path::new(""))
+            .join("backups")
+    }
+    // Every backup of this character, newest first.
+    fn list_backups( &self ) -> Vec<PathBuf> {
+        let stem = self.path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let Ok(entries) = std::fs::read_dir(self.backup_directory()) else {
+            return Vec::new();
+        };
+
+        let mut backups: Vec<(u64, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let timestamp = name.strip_prefix(&format!("{}-", stem))?
+                    .strip_suffix(".json")?
+                    .parse::<u64>().ok()?;
+
+                Some((timestamp, entry.path()))
+            })
+            .collect();
+        backups.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        backups.into_iter()
+            .map(|(_, path)| path)
+            .collect()
+    }
+    fn backup( &mut self, contents: &[u8] ) -> std::io::Result<()> {
+        let due = self.last_backup.is_none_or(|last_backup| last_backup.elapsed() >= self.backup_interval);
+        if self.backups == 0 || !due {
+            return Ok(());
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let stem = self.path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        // Even a failed backup waits out the interval before it's tried again
+        self.last_backup = Some(Instant::now());
+        std::fs::create_dir_all(self.backup_directory())?;
+        write_atomically(&self.backup_directory().join(format!("{}-{}.json", stem, timestamp)), contents)?;
+
+        for old_backup in self.list_backups().into_iter().skip(self.backups) {
+            std::fs::remove_file(old_backup)?;
+        }
+
+        Ok(())
+    }
+    fn index_path( &self ) -> PathBuf {
+        self.path.with_extension("index.json")
+    }
+    fn memories( &self ) -> Result<Vec<memory::Memory>, StorageError> {
+        Ok(parse_character(&self.read()?, &self.path)?.memories)
+    }
+    // Reuses the saved index, if it was built from these memories.
+    fn load_index( &mut self, monikai: &mut Monikai ) {
+        if let Some(index) = std::fs::read_to_string(self.index_path()).ok()
+            .and_then(|index_json| memory::MemoryIndex::from_json(&index_json, &monikai.memories))
+        {
+            self.saved_index_generation = Some(index.generation());
+            monikai.index = index;
+        }
+    }
+    fn read( &self ) -> Result<String, StorageError> {
+        std::fs::read_to_string(&self.path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => StorageError::Missing(self.location()),
+                _ => StorageError::Io(format!("'{}': {}", self.path.display(), err))
+            })
+    }
+}
+impl Storage for JsonStorage {
+    fn migrate( &mut self, dry_run: bool ) -> Result<Vec<&'static migrations::Migration>, StorageError> {
+        let character_json_string = match self.read() {
+            Err(StorageError::Missing(_)) => return Ok(Vec::new()),
+            result => result?
+        };
+        let character: serde_json::Value = serde_json::from_str(&character_json_string)
+            .map_err(|err| StorageError::Parse(format!("'{}': {}", self.path.display(), err)))?;
+        let pending = migrations::pending(&character)
+            .map_err(|err| StorageError::Schema(format!("'{}': {}", self.path.display(), err)))?;
+        if dry_run || pending.is_empty() {
+            return Ok(pending);
+        }
+
+        // The file from before is kept, since older versions can't read the new one
+        let original_path = self.backup_directory().join(format!("{}-schema-{}.json",
+            self.path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default(),
+            migrations::schema_version(&character)));
+        std::fs::create_dir_all(self.backup_directory())
+            .and_then(|_| write_atomically(&original_path, character_json_string.as_bytes()))
+            .map_err(|err| StorageError::Io(format!("'{}': {}", original_path.display(), err)))?;
+
+        let monikai = parse_character(&character_json_string, &self.path)?;
+        self.save(&monikai)?;
+
+        Ok(pending)
+    }
+    fn load( &mut self ) -> Result<Monikai, StorageError> {
+        let mut monikai = parse_character(&self.read()?, &self.path)?;
+        self.load_index(&mut monikai);
+
+        Ok(monikai)
+    }
+    fn recover( &mut self ) -> Option<(Monikai, String)> {
+        for backup in self.list_backups() {
+            let Some(mut monikai) = std::fs::read_to_string(&backup).ok()
+                .and_then(|character_json_string| parse_character(&character_json_string, &backup).ok()) else {
+                continue;
+            };
+            self.load_index(&mut monikai);
+
+            return Some((monikai, backup.display().to_string()));
+        }
+
+        None
+    }
+    fn save( &mut self, monikai: &Monikai ) -> Result<(), StorageError> {
+        let character_json_string = serde_json::to_string_pretty(monikai)
+            .map_err(|err| StorageError::Parse(err.to_string()))?;
+        write_atomically(&self.path, character_json_string.as_bytes())
+            .map_err(|err| StorageError::Io(format!("'{}': {}", self.path.display(), err)))?;
+
+        // An empty index has nothing worth keeping
+        let generation = monikai.index.generation();
+        if self.saved_index_generation != Some(generation) && generation != 0 {
+            write_atomically(&self.index_path(), monikai.index.to_json().as_bytes())
+                .map_err(|err| StorageError::Io(format!("'{}': {}", self.index_path().display(), err)))?;
+            self.saved_index_generation = Some(generation);
+        }
+
+        // The character is saved either way, so a failed backup is only worth a mention
+        if let Err(err) = self.backup(character_json_string.as_bytes()) {
+            print::error(&format!("Unable to back up to '{}': {}", self.backup_directory().display(), err));
+        }
+
+        Ok(())
+    }
+
+    fn memories_between( &self, from: u64, to: u64 ) -> Result<Vec<memory::Memory>, StorageError> {
+        Ok(self.memories()?
+            .into_iter()
+            .filter(|memory| (from..=to).contains(&memory.timestamp))
+            .collect())
+    }
+    fn search_memories( &self, text: &str ) -> Result<Vec<memory::Memory>, StorageError> {
+        let text = text.to_lowercase();
+
+        Ok(self.memories()?
+            .into_iter()
+            .filter(|memory| {
+                [&memory.conversation, &memory.user_profile, &memory.interaction_summary].iter()
+                    .any(|field| field.to_lowercase().contains(&text))
+            })
+            .collect())
+    }
+    fn location( &self ) -> String {
+        self.path.display().to_string()
+    }
+}
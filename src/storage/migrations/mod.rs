@@ -0,0 +1,170 @@
+use std::fmt;
+use std::time::{ Duration, UNIX_EPOCH };
+use serde_json::Value;
+
+use crate::{ memory, profile };
+
+// The schema characters are saved with
+pub const SCHEMA_VERSION: u32 = 6;
+// The only model embeddings were made with before they recorded which
+pub const LEGACY_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+/*
+ One step in upgrading a saved character, from schema version 'from'
+ to the next one.
+
+ Each works on the character as plain JSON, so it can still read
+ whatever the old fields looked like.
+*/
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    apply: fn( &mut Value )
+}
+impl fmt::Display for Migration {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!(f, "{} (schema {} to {})", self.description, self.from, self.from + 1)
+    }
+}
+// In order, one per schema version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Record which model made each memory's embedding",
+        apply: record_embedding_model
+    },
+    Migration {
+        from: 1,
+        description: "Give each memory an ID, and room for tags and other metadata",
+        apply: identify_memories
+    },
+    Migration {
+        from: 2,
+        description: "Start keeping when each memory is recalled",
+        apply: start_recall_history
+    },
+    Migration {
+        from: 3,
+        description: "Make room for embedding each memory in chunks",
+        apply: make_room_for_chunks
+    },
+    Migration {
+        from: 4,
+        description: "Start a consolidated user profile from the memories' own",
+        apply: consolidate_profile
+    },
+    Migration {
+        from: 5,
+        description: "Start a store of facts extracted from conversations",
+        apply: start_knowledge
+    }
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // Saved by a newer version, which may have fields this one would drop
+    TooNew( u32 ),
+    NotACharacter
+}
+impl fmt::Display for MigrationError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            MigrationError::TooNew(version) => write!(f, "Saved with schema version {}, but only up to {} is understood", version, SCHEMA_VERSION),
+            MigrationError::NotACharacter => write!(f, "Not a character")
+        }
+    }
+}
+impl std::error::Error for MigrationError {}
+
+// Files from before versioning have no 'schema_version', and count as 0.
+pub fn schema_version( character: &Value ) -> u32 {
+    character.get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32
+}
+// The migrations from schema 'version' up to the current one, in the order they run.
+pub fn since( version: u32 ) -> Result<Vec<&'static Migration>, MigrationError> {
+    if version > SCHEMA_VERSION {
+        return Err(MigrationError::TooNew(version));
+    }
+
+    Ok(MIGRATIONS.iter()
+        .filter(|migration| migration.from >= version)
+        .collect())
+}
+// The migrations a character needs to be brought up to date.
+pub fn pending( character: &Value ) -> Result<Vec<&'static Migration>, MigrationError> {
+    if !character.is_object() {
+        return Err(MigrationError::NotACharacter);
+    }
+
+    since(schema_version(character))
+}
+// Brings a character up to date in place, returning the migrations that ran.
+pub fn migrate( character: &mut Value ) -> Result<Vec<&'static Migration>, MigrationError> {
+    let migrations = pending(character)?;
+
+    for migration in &migrations {
+        (migration.apply)(character);
+    }
+    character["schema_version"] = Value::from(SCHEMA_VERSION);
+
+    Ok(migrations)
+}
+
+// A new ID for a memory made at 'timestamp', so older memories still sort first.
+pub fn memory_id_at( timestamp: u64 ) -> memory::MemoryId {
+    memory::MemoryId::from_datetime(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
+
+fn memories( character: &mut Value ) -> impl Iterator<Item = &mut serde_json::Map<String, Value>> {
+    character.get_mut("memories")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+fn record_embedding_model( character: &mut Value ) {
+    for memory in memories(character) {
+        memory.entry("embedding_model")
+            .or_insert(Value::from(LEGACY_EMBEDDING_MODEL));
+    }
+}
+// Everything else new defaults to empty, so only the IDs need filling in.
+fn identify_memories( character: &mut Value ) {
+    for memory in memories(character) {
+        let timestamp = memory.get("timestamp").and_then(Value::as_u64).unwrap_or(0);
+
+        memory.entry("id")
+            .or_insert_with(|| Value::from(memory_id_at(timestamp).to_string()));
+    }
+}
+// Earlier reads weren't timed, so they're left counted in 'times_read' only.
+fn start_recall_history( character: &mut Value ) {
+    for memory in memories(character) {
+        memory.entry("recalls")
+            .or_insert(Value::Array(Vec::new()));
+    }
+}
+// Older memories keep their one embedding, so are recalled whole.
+fn make_room_for_chunks( character: &mut Value ) {
+    for memory in memories(character) {
+        memory.entry("chunks")
+            .or_insert(Value::Array(Vec::new()));
+    }
+}
+// Earlier migrations have already made the memories readable as they are now.
+fn consolidate_profile( character: &mut Value ) {
+    let memories: Vec<memory::Memory> = character.get("memories")
+        .and_then(|memories| serde_json::from_value(memories.clone()).ok())
+        .unwrap_or_default();
+
+    character["profile"] = serde_json::to_value(profile::Profile::from_memories(memories.iter())).unwrap();
+}
+// Facts are only extracted as conversations end, so older memories have none.
+fn start_knowledge( character: &mut Value ) {
+    if let Some(character) = character.as_object_mut() {
+        character.entry("knowledge")
+            .or_insert(serde_json::json!({ "facts": [] }));
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::time::Duration;
+
+use tokio::sync::{ mpsc, oneshot };
+
+use crate::config::{ StorageBackend, StorageConfig };
+use crate::memory;
+use crate::monikai::Monikai;
+
+mod json;
+pub mod migrations;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(String),
+    Parse(String),
+    Database(String),
+    // Nothing has been saved there yet
+    Missing(String),
+    // Saved in a way this version can't safely read or migrate
+    Schema(String)
+}
+impl fmt::Display for StorageError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            StorageError::Io(reason) => write!(f, "Unable to access storage: {}", reason),
+            StorageError::Parse(reason) => write!(f, "Unable to parse the character: {}", reason),
+            StorageError::Database(reason) => write!(f, "Database error: {}", reason),
+            StorageError::Missing(location) => write!(f, "No character has been saved to '{}' yet", location),
+            StorageError::Schema(reason) => write!(f, "Unable to read the character: {}", reason)
+        }
+    }
+}
+impl std::error::Error for StorageError {}
+impl From<rusqlite::Error> for StorageError {
+    fn from( err: rusqlite::Error ) -> Self {
+        StorageError::Database(err.to_string())
+    }
+}
+
+/*
+ Somewhere to keep a character between runs.
+
+ 'save' is called often, so backends that can should only write
+ whatever changed since they last loaded or saved.
+ Each save either lands completely or not at all, so a conversation
+ being turned into a memory is never half-saved.
+*/
+pub trait Storage: Send {
+    /*
+     Upgrades whatever is saved to the current schema, returning the
+     migrations that ran, or with 'dry_run', the ones that would have.
+
+     Nothing saved yet needs no migrations.
+    */
+    fn migrate( &mut self, dry_run: bool ) -> Result<Vec<&'static migrations::Migration>, StorageError>;
+    fn load( &mut self ) -> Result<Monikai, StorageError>;
+    // The newest backup that still loads, and where it was, for when 'load' can't parse the character.
+    fn recover( &mut self ) -> Option<(Monikai, String)> {
+        None
+    }
+    fn save( &mut self, monikai: &Monikai ) -> Result<(), StorageError>;
+    // Memories made between two times (in seconds since the Unix epoch), inclusive.
+    fn memories_between( &self, from: u64, to: u64 ) -> Result<Vec<memory::Memory>, StorageError>;
+    // Memories whose conversation, user profile or summary contain the text, ignoring case.
+    fn search_memories( &self, text: &str ) -> Result<Vec<memory::Memory>, StorageError>;
+    // Where the character is kept, for messages.
+    fn location( &self ) -> String;
+}
+
+pub fn open( config: &StorageConfig ) -> Result<Box<dyn Storage>, StorageError> {
+    match config.backend {
+        StorageBackend::Json => Ok(Box::new(
+            JsonStorage::new(&config.character_path)
+                .with_backups(config.backups, Duration::from_secs(config.backup_interval_secs))
+        )),
+        StorageBackend::Sqlite => Ok(Box::new(SqliteStorage::open(&config.database_path)?))
+    }
+}
+
+/*
+ Requests for whichever task owns the storage (see 'monikai_autosave'),
+ so that only that one task ever writes.
+*/
+pub enum StorageRequest {
+    Save( oneshot::Sender<Result<(), StorageError>> ),
+    Search( String, oneshot::Sender<Result<Vec<memory::Memory>, StorageError>> ),
+    // Saves anything unsaved, then stops
+    Shutdown( oneshot::Sender<()> )
+}
+#[derive(Debug, Clone)]
+pub struct StorageHandle {
+    requests: mpsc::UnboundedSender<StorageRequest>
+}
+impl StorageHandle {
+    pub fn new() -> ( Self, mpsc::UnboundedReceiver<StorageRequest> ) {
+        let (requests, receiver) = mpsc::unbounded_channel();
+
+        (Self { requests }, receiver)
+    }
+    async fn request<T>( &self, request: impl FnOnce(oneshot::Sender<T>) -> StorageRequest ) -> Option<T> {
+        let (reply, response) = oneshot::channel();
+        self.requests.send(request(reply)).ok()?;
+
+        response.await.ok()
+    }
+    // Saves now, whether or not anything changed.
+    pub async fn save( &self ) -> Result<(), StorageError> {
+        self.request(StorageRequest::Save).await
+            .unwrap_or(Err(StorageError::Io(String::from("Storage has shut down"))))
+    }
+    pub async fn search_memories( &self, text: &str ) -> Result<Vec<memory::Memory>, StorageError> {
+        self.request(|reply| StorageRequest::Search(text.to_string(), reply)).await
+            .unwrap_or(Err(StorageError::Io(String::from("Storage has shut down"))))
+    }
+    // Waits for anything unsaved to be saved, and the storage to be let go of.
+    pub async fn shutdown( &self ) {
+        self.request(StorageRequest::Shutdown).await;
+    }
+}
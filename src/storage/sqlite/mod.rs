@@ -0,0 +1,487 @@
+use std::collections::{ HashMap, HashSet };
+use std::path::{ Path, PathBuf };
+use rusqlite::{ params, Connection, OptionalExtension, Row, Transaction };
+
+use crate::{ knowledge, llm, memory, profile };
+use crate::monikai::Monikai;
+use super::{ migrations, Storage, StorageError };
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS character (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        description TEXT NOT NULL,
+        last_spoken_to INTEGER NOT NULL,
+        memory_index TEXT,
+        conversation_channels TEXT NOT NULL,
+        profile TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS memories (
+        id INTEGER PRIMARY KEY,
+        key INTEGER NOT NULL UNIQUE,
+        timestamp INTEGER NOT NULL,
+        conversation TEXT NOT NULL,
+        user_profile TEXT NOT NULL,
+        interaction_summary TEXT NOT NULL,
+        embedding BLOB NOT NULL,
+        embedding_model TEXT NOT NULL,
+        times_read INTEGER NOT NULL,
+        memory_id TEXT NOT NULL,
+        channels TEXT NOT NULL,
+        participants TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        last_accessed INTEGER,
+        recalls TEXT NOT NULL,
+        chunks TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS memories_by_timestamp ON memories (timestamp);
+    CREATE UNIQUE INDEX IF NOT EXISTS memories_by_memory_id ON memories (memory_id);
+    CREATE TABLE IF NOT EXISTS facts (
+        fact_id TEXT PRIMARY KEY,
+        subject TEXT NOT NULL,
+        predicate TEXT NOT NULL,
+        object TEXT NOT NULL,
+        confidence REAL NOT NULL,
+        sources TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS facts_by_subject ON facts (subject COLLATE NOCASE, predicate COLLATE NOCASE);
+    CREATE TABLE IF NOT EXISTS conversation (
+        position INTEGER PRIMARY KEY,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL
+    );
+";
+const MEMORY_COLUMNS: &str = "timestamp, conversation, user_profile, interaction_summary, embedding, embedding_model, times_read,
+    memory_id, channels, participants, tags, last_accessed, recalls, chunks";
+const FACT_COLUMNS: &str = "fact_id, subject, predicate, object, confidence, sources, timestamp";
+// What each of 'migrations' changes in the tables, in the same order
+const MIGRATIONS: &[fn( &Transaction ) -> rusqlite::Result<()>] = &[
+    |transaction| {
+        // Before, every embedding was made with 'migrations::LEGACY_EMBEDDING_MODEL'
+        transaction.execute_batch("ALTER TABLE memories ADD COLUMN embedding_model TEXT NOT NULL DEFAULT 'text-embedding-ada-002';")
+    },
+    |transaction| {
+        transaction.execute_batch("
+            ALTER TABLE character ADD COLUMN conversation_channels TEXT NOT NULL DEFAULT '[]';
+            ALTER TABLE memories ADD COLUMN memory_id TEXT NOT NULL DEFAULT '';
+            ALTER TABLE memories ADD COLUMN channels TEXT NOT NULL DEFAULT '[]';
+            ALTER TABLE memories ADD COLUMN participants TEXT NOT NULL DEFAULT '[]';
+            ALTER TABLE memories ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';
+            ALTER TABLE memories ADD COLUMN last_accessed INTEGER;")?;
+
+        let timestamps = transaction
+            .prepare("SELECT id, timestamp FROM memories")?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<rusqlite::Result<Vec<(i64, u64)>>>()?;
+        for (row_id, timestamp) in timestamps {
+            transaction.execute("UPDATE memories SET memory_id = ?1 WHERE id = ?2",
+                params![migrations::memory_id_at(timestamp).to_string(), row_id])?;
+        }
+
+        transaction.execute_batch("CREATE UNIQUE INDEX memories_by_memory_id ON memories (memory_id);")
+    },
+    |transaction| {
+        transaction.execute_batch("ALTER TABLE memories ADD COLUMN recalls TEXT NOT NULL DEFAULT '[]';")
+    },
+    |transaction| {
+        transaction.execute_batch("ALTER TABLE memories ADD COLUMN chunks TEXT NOT NULL DEFAULT '[]';")
+    },
+    |transaction| {
+        transaction.execute_batch("ALTER TABLE character ADD COLUMN profile TEXT NOT NULL DEFAULT '{}';")?;
+
+        let memories = transaction
+            .prepare("SELECT memory_id, user_profile, timestamp FROM memories ORDER BY timestamp, id")?
+            .query_map([], |row| Ok(memory::Memory {
+                id: row.get::<_, String>(0)?.parse().unwrap_or_default(),
+                user_profile: row.get(1)?,
+                timestamp: row.get::<_, i64>(2)? as u64,
+                ..memory::Memory::default()
+            }))?
+            .collect::<rusqlite::Result<Vec<memory::Memory>>>()?;
+        transaction.execute("UPDATE character SET profile = ?1",
+            params![serde_json::to_string(&profile::Profile::from_memories(memories.iter())).unwrap()])?;
+
+        Ok(())
+    },
+    |transaction| {
+        // Nothing was extracted before, so the table starts out empty
+        transaction.execute_batch("
+            CREATE TABLE facts (
+                fact_id TEXT PRIMARY KEY,
+                subject TEXT NOT NULL,
+                predicate TEXT NOT NULL,
+                object TEXT NOT NULL,
+                confidence REAL NOT NULL,
+                sources TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
+            );
+            CREATE INDEX facts_by_subject ON facts (subject COLLATE NOCASE, predicate COLLATE NOCASE);")
+    }
+];
+
+// What can change about a memory once it's been made
+#[derive(PartialEq)]
+struct MemoryState {
+    times_read: usize,
+    last_accessed: Option<u64>,
+    recalls: Vec<u64>,
+    tags: Vec<String>
+}
+impl MemoryState {
+    fn of( memory: &memory::Memory ) -> Self {
+        Self {
+            times_read: memory.times_read,
+            last_accessed: memory.last_accessed,
+            recalls: memory.recalls.clone(),
+            tags: memory.tags.clone()
+        }
+    }
+}
+
+/*
+ The character in an SQLite database, with a row per memory, per fact
+ and per message of the current conversation.
+
+ Remembers what's already in the database, so each save only inserts,
+ updates or deletes the rows that changed, all in one transaction.
+
+ The schema version is kept in 'PRAGMA user_version'. Before migrating,
+ a copy of the database is kept in 'backups/' as '<name>-schema-<version>.db'.
+*/
+pub struct SqliteStorage {
+    connection: Connection,
+    path: PathBuf,
+    saved_character: Option<(String, u64, Vec<memory::Channel>, profile::Profile)>,
+    // Each saved memory's key, and what of it could have changed since
+    saved_memories: HashMap<u64, MemoryState>,
+    saved_facts: HashMap<knowledge::FactId, knowledge::Fact>,
+    // The role and content of each saved turn of the conversation
+    saved_turns: Vec<(String, String)>,
+    saved_index_generation: Option<u64>
+}
+
+// Lists are kept as JSON text.
+fn json_column<T: serde::de::DeserializeOwned>( row: &Row, column: usize ) -> rusqlite::Result<T> {
+    serde_json::from_str(&row.get::<_, String>(column)?)
+        .map_err(|err| rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(err)))
+}
+fn memory_from_row( row: &Row ) -> rusqlite::Result<memory::Memory> {
+    let embedding_bytes: Vec<u8> = row.get(4)?;
+
+    Ok(memory::Memory {
+        timestamp: row.get::<_, i64>(0)? as u64,
+        conversation: row.get(1)?,
+        user_profile: row.get(2)?,
+        interaction_summary: row.get(3)?,
+        embedding: memory::Embedding::from_bytes(&embedding_bytes)
+            .ok_or(rusqlite::Error::InvalidColumnType(4, String::from("embedding"), rusqlite::types::Type::Blob))?,
+        embedding_model: row.get(5)?,
+        times_read: row.get::<_, i64>(6)? as usize,
+        id: row.get::<_, String>(7)?.parse()
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(7, rusqlite::types::Type::Text, Box::new(err)))?,
+        channels: json_column(row, 8)?,
+        participants: json_column(row, 9)?,
+        tags: json_column(row, 10)?,
+        last_accessed: row.get::<_, Option<i64>>(11)?.map(|last_accessed| last_accessed as u64),
+        recalls: json_column(row, 12)?,
+        chunks: json_column(row, 13)?
+    })
+}
+fn fact_from_row( row: &Row ) -> rusqlite::Result<knowledge::Fact> {
+    Ok(knowledge::Fact {
+        id: row.get::<_, String>(0)?.parse()
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err)))?,
+        subject: row.get(1)?,
+        predicate: row.get(2)?,
+        object: row.get(3)?,
+        confidence: row.get(4)?,
+        sources: json_column(row, 5)?,
+        timestamp: row.get::<_, i64>(6)? as u64
+    })
+}
+// Escapes LIKE wildcards, so the text is matched as is.
+fn like_pattern( text: &str ) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+
+    format!("%{}%", escaped)
+}
+
+impl SqliteStorage {
+    // Opens the database, creating it if needed.
+    pub fn open( path: &Path ) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+
+        // A new database starts out on the current schema, while an existing one is left for 'migrate'
+        let created = connection
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'character'", [], |row| row.get::<_, i64>(0))? == 0;
+        if created {
+            connection.execute_batch(&format!("BEGIN; {} PRAGMA user_version = {}; COMMIT;", SCHEMA, migrations::SCHEMA_VERSION))?;
+        }
+
+        let mut storage = Self {
+            connection,
+            path: path.to_path_buf(),
+            saved_character: None,
+            saved_memories: HashMap::new(),
+            saved_facts: HashMap::new(),
+            saved_turns: Vec::new(),
+            saved_index_generation: None
+        };
+        let version: u32 = storage.connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version == migrations::SCHEMA_VERSION {
+            storage.read_saved()?;
+        }
+
+        Ok(storage)
+    }
+    // Whatever is already there counts as saved, so the first save only writes the difference.
+    fn read_saved( &mut self ) -> Result<(), StorageError> {
+        self.saved_character = self.connection
+            .query_row("SELECT description, last_spoken_to, conversation_channels, profile FROM character WHERE id = 0", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, json_column(row, 2)?, json_column(row, 3)?))
+            })
+            .optional()?;
+        self.saved_memories = self.connection
+            .prepare("SELECT key, times_read, last_accessed, recalls, tags FROM memories")?
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, MemoryState {
+                    times_read: row.get::<_, i64>(1)? as usize,
+                    last_accessed: row.get::<_, Option<i64>>(2)?.map(|last_accessed| last_accessed as u64),
+                    recalls: json_column(row, 3)?,
+                    tags: json_column(row, 4)?
+                }))
+            })?
+            .collect::<rusqlite::Result<HashMap<u64, MemoryState>>>()?;
+        self.saved_facts = self.connection
+            .prepare(&format!("SELECT {} FROM facts", FACT_COLUMNS))?
+            .query_map([], fact_from_row)?
+            .map(|fact| fact.map(|fact| (fact.id, fact)))
+            .collect::<rusqlite::Result<HashMap<knowledge::FactId, knowledge::Fact>>>()?;
+        self.saved_turns = self.connection
+            .prepare("SELECT role, content FROM conversation ORDER BY position")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+        Ok(())
+    }
+}
+impl Storage for SqliteStorage {
+    fn migrate( &mut self, dry_run: bool ) -> Result<Vec<&'static migrations::Migration>, StorageError> {
+        let version: u32 = self.connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let pending = migrations::since(version)
+            .map_err(|err| StorageError::Schema(format!("'{}': {}", self.location(), err)))?;
+        if dry_run || pending.is_empty() {
+            return Ok(pending);
+        }
+
+        // The database from before is kept, since older versions can't read the new one
+        let backup_directory = self.path.parent()
+            .unwrap_or(Path::new(""))
+            .join("backups");
+        let original_path = backup_directory.join(format!("{}-schema-{}.db",
+            self.path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default(),
+            version));
+        std::fs::create_dir_all(&backup_directory)
+            .and_then(|_| match std::fs::remove_file(&original_path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(())
+            })
+            .map_err(|err| StorageError::Io(format!("'{}': {}", original_path.display(), err)))?;
+        self.connection.execute("VACUUM INTO ?1", params![original_path.to_string_lossy()])?;
+
+        let transaction = self.connection.transaction()?;
+        for migration in &pending {
+            MIGRATIONS[migration.from as usize](&transaction)?;
+        }
+        transaction.pragma_update(None, "user_version", migrations::SCHEMA_VERSION)?;
+        transaction.commit()?;
+        self.read_saved()?;
+
+        Ok(pending)
+    }
+    fn load( &mut self ) -> Result<Monikai, StorageError> {
+        let Some((description, last_spoken_to, index_json, conversation_channels, profile)) = self.connection
+            .query_row("SELECT description, last_spoken_to, memory_index, conversation_channels, profile FROM character WHERE id = 0", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, Option<String>>(2)?, json_column(row, 3)?, json_column(row, 4)?))
+            })
+            .optional()? else {
+            return Err(StorageError::Missing(self.location()));
+        };
+
+        let memories = self.connection
+            .prepare(&format!("SELECT {} FROM memories ORDER BY timestamp, id", MEMORY_COLUMNS))?
+            .query_map([], memory_from_row)?
+            .collect::<rusqlite::Result<Vec<memory::Memory>>>()?;
+        let facts = self.connection
+            .prepare(&format!("SELECT {} FROM facts ORDER BY rowid", FACT_COLUMNS))?
+            .query_map([], fact_from_row)?
+            .collect::<rusqlite::Result<Vec<knowledge::Fact>>>()?;
+        let current_conversation = self.connection
+            .prepare("SELECT role, content FROM conversation ORDER BY position")?
+            .query_map([], |row| Ok(llm::Message { role: row.get(0)?, content: row.get(1)? }))?
+            .collect::<rusqlite::Result<Vec<llm::Message>>>()?;
+
+        let index = index_json
+            .and_then(|index_json| memory::MemoryIndex::from_json(&index_json, &memories));
+        self.saved_index_generation = index.as_ref().map(|index| index.generation());
+
+        Ok(Monikai {
+            schema_version: migrations::SCHEMA_VERSION,
+            description,
+            profile,
+            knowledge: knowledge::Knowledge { facts },
+            memories,
+            current_conversation,
+            conversation_channels,
+            last_spoken_to,
+            providers: llm::Providers::default(),
+            retrieval: Default::default(),
+            prompt: Default::default(),
+            index: index.unwrap_or_default(),
+            conversation_summary: Default::default(),
+            version: 0
+        })
+    }
+    fn save( &mut self, monikai: &Monikai ) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+
+        let character = (monikai.description.clone(), monikai.last_spoken_to, monikai.conversation_channels.clone(), monikai.profile.clone());
+        if self.saved_character.as_ref() != Some(&character) {
+            transaction.execute(
+                "INSERT INTO character (id, description, last_spoken_to, conversation_channels, profile) VALUES (0, ?1, ?2, ?3, ?4)
+                    ON CONFLICT (id) DO UPDATE SET description = excluded.description, last_spoken_to = excluded.last_spoken_to,
+                        conversation_channels = excluded.conversation_channels, profile = excluded.profile",
+                params![character.0, character.1 as i64, serde_json::to_string(&character.2).unwrap(), serde_json::to_string(&character.3).unwrap()])?;
+        }
+
+        // Pruned memories are deleted, new ones inserted, and read counts and tags kept up to date
+        let keys: HashSet<u64> = monikai.memories.iter().map(memory::Memory::key).collect();
+        for key in self.saved_memories.keys().filter(|key| !keys.contains(key)) {
+            transaction.execute("DELETE FROM memories WHERE key = ?1", params![*key as i64])?;
+        }
+        for memory in &monikai.memories {
+            match self.saved_memories.get(&memory.key()) {
+                None => {
+                    transaction.execute(
+                        &format!("INSERT OR REPLACE INTO memories (key, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)", MEMORY_COLUMNS),
+                        params![
+                            memory.key() as i64,
+                            memory.timestamp as i64,
+                            memory.conversation,
+                            memory.user_profile,
+                            memory.interaction_summary,
+                            memory.embedding.to_bytes(),
+                            memory.embedding_model,
+                            memory.times_read as i64,
+                            memory.id.to_string(),
+                            serde_json::to_string(&memory.channels).unwrap(),
+                            serde_json::to_string(&memory.participants).unwrap(),
+                            serde_json::to_string(&memory.tags).unwrap(),
+                            memory.last_accessed.map(|last_accessed| last_accessed as i64),
+                            serde_json::to_string(&memory.recalls).unwrap(),
+                            serde_json::to_string(&memory.chunks).unwrap()
+                        ])?;
+                },
+                Some(state) if *state != MemoryState::of(memory) => {
+                    transaction.execute(
+                        "UPDATE memories SET times_read = ?1, last_accessed = ?2, recalls = ?3, tags = ?4 WHERE key = ?5",
+                        params![
+                            memory.times_read as i64,
+                            memory.last_accessed.map(|last_accessed| last_accessed as i64),
+                            serde_json::to_string(&memory.recalls).unwrap(),
+                            serde_json::to_string(&memory.tags).unwrap(),
+                            memory.key() as i64
+                        ])?;
+                },
+                Some(_) => {}
+            }
+        }
+
+        // Facts are learned, corrected and removed, each by ID, and updated in place to keep their order
+        for id in self.saved_facts.keys().filter(|id| monikai.knowledge.fact(**id).is_none()) {
+            transaction.execute("DELETE FROM facts WHERE fact_id = ?1", params![id.to_string()])?;
+        }
+        for fact in &monikai.knowledge.facts {
+            let statement = match self.saved_facts.get(&fact.id) {
+                None => format!("INSERT INTO facts ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", FACT_COLUMNS),
+                Some(saved) if saved != fact => String::from("UPDATE facts SET subject = ?2, predicate = ?3, object = ?4, confidence = ?5,
+                    sources = ?6, timestamp = ?7 WHERE fact_id = ?1"),
+                Some(_) => continue
+            };
+            transaction.execute(&statement, params![
+                fact.id.to_string(),
+                fact.subject,
+                fact.predicate,
+                fact.object,
+                fact.confidence,
+                serde_json::to_string(&fact.sources).unwrap(),
+                fact.timestamp as i64
+            ])?;
+        }
+
+        // Only turns after where the conversation stopped matching what was saved are rewritten,
+        // so a conversation ended and started over is never mixed with the one before
+        let first_new_turn = monikai.current_conversation.iter()
+            .zip(&self.saved_turns)
+            .take_while(|(message, (role, content))| message.role == *role && message.content == *content)
+            .count();
+        if first_new_turn < self.saved_turns.len() {
+            transaction.execute("DELETE FROM conversation WHERE position >= ?1", params![first_new_turn as i64])?;
+        }
+        for (position, message) in monikai.current_conversation.iter().enumerate().skip(first_new_turn) {
+            transaction.execute(
+                "INSERT INTO conversation (position, role, content) VALUES (?1, ?2, ?3)",
+                params![position as i64, message.role, message.content])?;
+        }
+
+        let generation = monikai.index.generation();
+        if self.saved_index_generation != Some(generation) && generation != 0 {
+            transaction.execute("UPDATE character SET memory_index = ?1 WHERE id = 0", params![monikai.index.to_json()])?;
+        }
+
+        transaction.commit()?;
+
+        // Only once it's all committed does it count as saved
+        self.saved_character = Some(character);
+        self.saved_memories = monikai.memories.iter()
+            .map(|memory| (memory.key(), MemoryState::of(memory)))
+            .collect();
+        self.saved_facts = monikai.knowledge.facts.iter()
+            .map(|fact| (fact.id, fact.clone()))
+            .collect();
+        self.saved_turns = monikai.current_conversation.iter()
+            .map(|message| (message.role.clone(), message.content.clone()))
+            .collect();
+        if generation != 0 {
+            self.saved_index_generation = Some(generation);
+        }
+
+        Ok(())
+    }
+    fn memories_between( &self, from: u64, to: u64 ) -> Result<Vec<memory::Memory>, StorageError> {
+        // SQLite integers are signed, so far-off times are clamped
+        let (from, to) = (from.min(i64::MAX as u64) as i64, to.min(i64::MAX as u64) as i64);
+
+        Ok(self.connection
+            .prepare(&format!("SELECT {} FROM memories WHERE timestamp BETWEEN ?1 AND ?2 ORDER BY timestamp, id", MEMORY_COLUMNS))?
+            .query_map(params![from, to], memory_from_row)?
+            .collect::<rusqlite::Result<Vec<memory::Memory>>>()?)
+    }
+    fn search_memories( &self, text: &str ) -> Result<Vec<memory::Memory>, StorageError> {
+        Ok(self.connection
+            .prepare(&format!("
+                SELECT {} FROM memories
+                WHERE lower(conversation) LIKE lower(?1) ESCAPE '\\'
+                    OR lower(user_profile) LIKE lower(?1) ESCAPE '\\'
+                    OR lower(interaction_summary) LIKE lower(?1) ESCAPE '\\'
+                ORDER BY timestamp, id", MEMORY_COLUMNS))?
+            .query_map(params![like_pattern(text)], memory_from_row)?
+            .collect::<rusqlite::Result<Vec<memory::Memory>>>()?)
+    }
+    fn location( &self ) -> String {
+        self.path.display().to_string()
+    }
+}
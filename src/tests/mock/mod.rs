@@ -0,0 +1,273 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use std::time::Duration;
+use axum::{
+    body::Body,
+    extract::State,
+    http::StatusCode,
+    response::{ IntoResponse, Response },
+    routing::post,
+    Json,
+    Router,
+};
+use serde_json::{ json, Value };
+use tokio::sync::oneshot;
+
+use crate::{ Arc, Mutex };
+use crate::{ config, llm, openai };
+
+pub const EMBEDDING_DIMENSIONS: usize = 64;
+
+/*
+ Scripted answers for the mock server.
+
+ Each rule is a (needle, answer) pair, where the first rule whose needle
+ appears in the prompt (or the last chat message) wins.
+ Anything unmatched falls back to a fixed, deterministic answer.
+
+ Failures are (endpoint, status, times) triples, failing the first
+ 'times' requests to that endpoint with the given status. Completions
+ whose prompt contains any of 'failing_completions' always fail, with 400.
+ Failures ask to be retried after 'retry_after', rounded down to seconds.
+
+ Every answer can be held back by a fixed latency, to mimic a slow model.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    pub chat: Vec<(String, String)>,
+    pub completions: Vec<(String, String)>,
+    pub failures: Vec<(&'static str, u16, usize)>,
+    pub failing_completions: Vec<String>,
+    pub retry_after: Duration,
+    pub latency: Duration
+}
+impl Script {
+    pub fn chat( mut self, needle: &str, answer: &str ) -> Self {
+        self.chat.push((needle.to_string(), answer.to_string()));
+        self
+    }
+    pub fn completion( mut self, needle: &str, answer: &str ) -> Self {
+        self.completions.push((needle.to_string(), answer.to_string()));
+        self
+    }
+    pub fn fail( mut self, endpoint: &'static str, status: u16, times: usize ) -> Self {
+        self.failures.push((endpoint, status, times));
+        self
+    }
+    pub fn fail_completion( mut self, needle: &str ) -> Self {
+        self.failing_completions.push(needle.to_string());
+        self
+    }
+    pub fn retry_after( mut self, retry_after: Duration ) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+    pub fn latency( mut self, latency: Duration ) -> Self {
+        self.latency = latency;
+        self
+    }
+    fn answer( rules: &[(String, String)], input: &str, fallback: &str ) -> String {
+        rules.iter()
+            .find(|(needle, _)| input.contains(needle.as_str()))
+            .map(|(_, answer)| answer.clone())
+            .unwrap_or(fallback.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub endpoint: &'static str,
+    pub body: Value
+}
+
+struct MockState {
+    script: Script,
+    requests: Mutex<Vec<RecordedRequest>>
+}
+impl MockState {
+    // Records the request, and returns the scripted failure for it if there is one.
+    async fn record( &self, endpoint: &'static str, body: Value ) -> Option<Response> {
+        tokio::time::sleep(self.script.latency).await;
+
+        let mut requests = self.requests.lock().await;
+        requests.push(RecordedRequest { endpoint, body });
+
+        let seen = requests.iter()
+            .filter(|request| request.endpoint == endpoint)
+            .count();
+
+        self.script.failures.iter()
+            .find(|(failing_endpoint, _, times)| *failing_endpoint == endpoint && seen <= *times)
+            .map(|(_, status, _)| {
+                let status = StatusCode::from_u16(*status).unwrap();
+
+                (status, [("Retry-After", self.script.retry_after.as_secs().to_string())], Json(json!({ "error": { "message": "Scripted failure" } })))
+                    .into_response()
+            })
+    }
+}
+
+/*
+ An OpenAI-compatible stand-in for '/v1/chat/completions', '/v1/completions'
+ and '/v1/embeddings', so tests can run offline.
+
+ Runs on its own thread and runtime, so it outlives whichever runtime
+ the test happens to use. Shuts down when dropped.
+*/
+pub struct MockServer {
+    pub base_url: String,
+    state: Arc<MockState>,
+    shutdown: Option<oneshot::Sender<()>>
+}
+impl MockServer {
+    pub fn start( script: Script ) -> Self {
+        let state = Arc::new(MockState {
+            script,
+            requests: Mutex::new(Vec::new())
+        });
+        let app = Router::new()
+            .route("/v1/chat/completions", post(chat_completions))
+            .route("/v1/completions", post(completions))
+            .route("/v1/embeddings", post(embeddings))
+            .with_state(state.clone());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("Unable to bind the mock server!");
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, shutdown_signal) = oneshot::channel::<()>();
+
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(async { shutdown_signal.await.ok(); })
+                        .await
+                        .unwrap();
+                });
+        });
+
+        Self {
+            base_url: format!("http://{}/v1", address),
+            state,
+            shutdown: Some(shutdown)
+        }
+    }
+    pub fn config( &self ) -> config::OpenAiConfig {
+        config::OpenAiConfig {
+            base_url: self.base_url.clone(),
+            api_key: Some(String::from("mock-key")),
+            ..config::OpenAiConfig::default()
+        }
+    }
+    // A client for the given config, which retries without delay.
+    pub fn openai( &self, config: &config::OpenAiConfig ) -> openai::OpenAi {
+        let mut openai = openai::OpenAi::from_config(config);
+        openai.retry_policy.base_delay = Duration::from_millis(1);
+
+        openai
+    }
+    pub fn providers( &self ) -> llm::Providers {
+        llm::Providers::from_single(self.openai(&self.config()))
+    }
+    pub async fn requests( &self, endpoint: &str ) -> Vec<RecordedRequest> {
+        self.state.requests.lock().await
+            .iter()
+            .filter(|request| request.endpoint == endpoint)
+            .cloned()
+            .collect()
+    }
+}
+impl Drop for MockServer {
+    fn drop( &mut self ) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+// A bag-of-words hash, so texts sharing words end up with similar embeddings.
+pub fn mock_embedding( input: &str ) -> Vec<f64> {
+    let mut embedding = vec![0f64; EMBEDDING_DIMENSIONS];
+
+    for word in input.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let mut hasher = DefaultHasher::new();
+        word.to_lowercase().hash(&mut hasher);
+
+        embedding[(hasher.finish() % EMBEDDING_DIMENSIONS as u64) as usize] += 1f64;
+    }
+
+    embedding
+}
+
+async fn chat_completions( State(state): State<Arc<MockState>>, Json(body): Json<Value> ) -> Response {
+    let last_message = body["messages"]
+        .as_array()
+        .and_then(|messages| messages.last())
+        .and_then(|message| message["content"].as_str())
+        .unwrap_or_default()
+        .to_string();
+    let answer = Script::answer(&state.script.chat, &last_message, &format!("You said: {}", last_message));
+
+    let stream = body["stream"].as_bool().unwrap_or(false);
+
+    if let Some(failure) = state.record("chat/completions", body).await {
+        return failure;
+    }
+
+    // Streams word by word, with each event split across two chunks to keep parsers honest
+    if stream {
+        let mut chunks: Vec<Result<String, std::convert::Infallible>> = Vec::new();
+
+        for word in answer.split_inclusive(' ') {
+            let event = format!("data: {}\n\n", json!({ "choices": [{ "delta": { "content": word } }] }));
+            let (first_half, second_half) = event.split_at(event.len() / 2);
+
+            chunks.push(Ok(first_half.to_string()));
+            chunks.push(Ok(second_half.to_string()));
+        }
+        chunks.push(Ok(String::from("data: [DONE]\n\n")));
+
+        return (
+            [("Content-Type", "text/event-stream")],
+            Body::from_stream(futures::stream::iter(chunks))
+        ).into_response();
+    }
+
+    Json(json!({
+        "choices": [{ "message": { "role": "assistant", "content": answer } }]
+    })).into_response()
+}
+async fn completions( State(state): State<Arc<MockState>>, Json(body): Json<Value> ) -> Response {
+    let prompt = body["prompt"].as_str().unwrap_or_default();
+    let answer = Script::answer(&state.script.completions, prompt, "Nothing of note.");
+
+    let failing = state.script.failing_completions.iter().any(|needle| prompt.contains(needle.as_str()));
+
+    if let Some(failure) = state.record("completions", body).await {
+        return failure;
+    }
+    if failing {
+        return (StatusCode::BAD_REQUEST, Json(json!({ "error": { "message": "Scripted failure" } }))).into_response();
+    }
+
+    Json(json!({
+        "choices": [{ "text": answer }]
+    })).into_response()
+}
+async fn embeddings( State(state): State<Arc<MockState>>, Json(body): Json<Value> ) -> Response {
+    let embedding = mock_embedding(body["input"].as_str().unwrap_or_default());
+
+    if let Some(failure) = state.record("embeddings", body).await {
+        return failure;
+    }
+
+    Json(json!({
+        "data": [{ "embedding": embedding }]
+    })).into_response()
+}
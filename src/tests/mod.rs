@@ -1,4 +1,11 @@
+mod mock;
+
+use std::fs::{ File, OpenOptions };
+use std::io::Read;
+
 use crate::*;
+use crate::storage::Storage;
+use mock::{ MockServer, Script };
 
 #[test]
 fn initialize() -> Result<(), ()>{
@@ -13,17 +20,1285 @@ fn initialize() -> Result<(), ()>{
     Ok(())
 }
 
+// A memory of a conversation, with its own ID, embedded as given.
+fn memory( embedding: Vec<f64>, conversation: &str ) -> memory::Memory {
+    memory::Memory {
+        id: memory::MemoryId::new(),
+        embedding: memory::Embedding::from(embedding),
+        conversation: conversation.to_string(),
+        ..memory::Memory::default()
+    }
+}
+
+// A character with nothing but its description, memories and providers.
+fn character( providers: llm::Providers, memories: Vec<memory::Memory> ) -> monikai::Monikai {
+    monikai::Monikai {
+        description: String::from("You're Monika from DDLC."),
+        memories,
+        providers,
+        ..monikai::Monikai::default()
+    }
+}
+
 #[tokio::test]
 async fn build_memory() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("USER PROFILE:", "I have learned that the MC enjoys science fiction.")
+        .completion("INTERACTION SUMMARY:", "We talked about Dune."));
     let conversation = "MC: Hello!\nMonika: Hi!\nMC:Do you have any good book recommendations?\nMonika: Dune - Frank Herbert!!";
 
-    let memory = memory::Memory::new(conversation.to_string()).await;
+    let memory = memory::Memory::new(conversation.to_string(), &server.providers()).await
+        .map_err(|_| ())?;
     
     println!("{:?}", memory);
+
+    assert_eq!(memory.user_profile, "I have learned that the MC enjoys science fiction.");
+    assert_eq!(memory.interaction_summary, "We talked about Dune.");
+    assert_eq!(memory.embedding, memory::Embedding::from(mock::mock_embedding(conversation)));
     
     Ok(())
 }
 
+#[tokio::test]
+async fn conversation_to_memory() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .chat("recommend a book", "You should read the cookbook 'The Joy of Cooking'!")
+        .completion("incredibly short phrase", "\"memory_check_phrase\": \"cookbook\"}")
+        .completion("USER PROFILE:", "The MC wants to learn to cook.")
+        .completion("INTERACTION SUMMARY:", "We talked about cookbooks."));
+    let mut monikai = monikai::Monikai {
+        // The memory shares one word of many with the key phrase
+        retrieval: config::RetrievalConfig { min_similarity: 0.1, ..config::RetrievalConfig::default() },
+        ..character(server.providers(), Vec::new())
+    };
+
+    let response = monikai.send_message(String::from("Can you recommend a book?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+    assert_eq!(response, "You should read the cookbook 'The Joy of Cooking'!");
+    assert_eq!(monikai.current_conversation.len(), 2);
+
+    monikai.end_conversation().await
+        .map_err(|_| ())?;
+    assert!(monikai.current_conversation.is_empty());
+    assert_eq!(monikai.memories.len(), 1);
+    assert_eq!(monikai.memories[0].user_profile, "The MC wants to learn to cook.");
+    assert!(monikai.memories[0].conversation.contains("The Joy of Cooking"));
+
+    // The new memory should be the one recalled by the next message
+    let response = monikai.send_message(String::from("What was that cookbook called?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+    assert_eq!(response, "You said: What was that cookbook called?");
+
+    let chat_requests = server.requests("chat/completions").await;
+    assert_eq!(chat_requests.len(), 2);
+    assert!(chat_requests[1].body.to_string().contains("related memories"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recall_across_embedding_dimensions() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("incredibly short phrase", "\"memory_check_phrase\": \"cookbook\"}"));
+    let mut monikai = character(server.providers(), vec!(
+        memory(mock::mock_embedding("cookbook"), "We talked about a cookbook."),
+        // Embedded by some older model, so it can't be compared
+        memory(vec![1f64; mock::EMBEDDING_DIMENSIONS * 2], "We talked about the weather."),
+        memory(vec![0f64; mock::EMBEDDING_DIMENSIONS], "We didn't talk at all.")
+    ));
+
+    monikai.send_message(String::from("What was that cookbook called?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+
+    let chat_requests = server.requests("chat/completions").await;
+    let prompt = chat_requests[0].body.to_string();
+    assert!(prompt.contains("We talked about a cookbook."));
+    assert!(!prompt.contains("We talked about the weather."));
+    assert!(!prompt.contains("We didn't talk at all."));
+
+    // Recalling never reorders the memories themselves
+    let conversations: Vec<&str> = monikai.memories.iter().map(|memory| memory.conversation.as_str()).collect();
+    assert_eq!(conversations, vec!("We talked about a cookbook.", "We talked about the weather.", "We didn't talk at all."));
+
+    Ok(())
+}
+
+#[test]
+fn retrieve_top_memories() {
+    let memories = vec!(
+        memory(vec![0., 1., 0.], ""),
+        memory(vec![1., 0.25, 0.], ""),
+        memory(vec![1., 0., 0.3], ""),
+        memory(vec![1., 0.2, 0.], "")
+    );
+    let index = memory::MemoryIndex::build(&memories);
+    let query = memory::Embedding::from(vec![1., 0., 0.]);
+
+    // Most similar first, without the unrelated memory
+    let config = config::RetrievalConfig { top_k: 10, min_similarity: 0.5, ..config::RetrievalConfig::default() };
+    let recalled: Vec<usize> = index.search(&query, &config).into_iter().map(|(index, _)| index).collect();
+    assert_eq!(recalled, vec!(3, 1, 2));
+
+    let config = config::RetrievalConfig { top_k: 2, min_similarity: 0.5, ..config::RetrievalConfig::default() };
+    let recalled: Vec<usize> = index.search(&query, &config).into_iter().map(|(index, _)| index).collect();
+    assert_eq!(recalled, vec!(3, 1));
+
+    // Diversity prefers the memory leaning the other way over a near-duplicate
+    let config = config::RetrievalConfig { top_k: 2, min_similarity: 0.5, mmr_lambda: Some(0.5), ..config::RetrievalConfig::default() };
+    let recalled: Vec<usize> = index.search(&query, &config).into_iter().map(|(index, _)| index).collect();
+    assert_eq!(recalled, vec!(3, 2));
+
+    let config = config::RetrievalConfig { min_similarity: 1.1, ..config::RetrievalConfig::default() };
+    assert!(index.search(&query, &config).is_empty());
+}
+
+#[test]
+fn hybrid_search() {
+    let mut memories = vec!(
+        memory(vec![1., 0., 0.], "user: I baked a pretzel for lunch."),
+        memory(vec![0., 1., 0.], "assistant: You should read The College Cookbook!"),
+        memory(vec![0.9, 0.1, 0.], "user: Any cookbook for baking bread?"),
+        memory(vec![0., 0., 1.], "user: My locker number is 4127.")
+    );
+    let mut index = memory::MemoryIndex::build(&memories);
+    let query = memory::Embedding::from(vec![1., 0., 0.]);
+    let config = config::RetrievalConfig { top_k: 3, min_similarity: 0.8, ..config::RetrievalConfig::default() };
+    let recalled = |index: &memory::MemoryIndex, text: &str, config: &config::RetrievalConfig| -> Vec<usize> {
+        index.hybrid_search(text, &query, config).into_iter().map(|(index, _)| index).collect()
+    };
+
+    // Found by both comes first, then the top of either, even if the embedding is unrelated
+    assert_eq!(recalled(&index, "college cookbook", &config), vec!(2, 0, 1));
+    assert_eq!(recalled(&index, "locker 4127", &config), vec!(0, 3, 2));
+    // Without any matching words, it's the same as searching by meaning
+    assert_eq!(recalled(&index, "the", &config), vec!(0, 2));
+    // And memories only found by words need enough of them, since 'min_similarity' doesn't hold them back
+    let strict = config::RetrievalConfig { min_term_match: 1., ..config.clone() };
+    assert_eq!(recalled(&index, "locker pretzel", &config), vec!(0, 2, 3));
+    assert_eq!(recalled(&index, "locker pretzel", &strict), vec!(0, 2));
+
+    let semantic = config::RetrievalConfig { lexical_search: false, ..config.clone() };
+    assert_eq!(index.hybrid_search("college cookbook", &query, &semantic), index.search(&query, &semantic));
+
+    // Pruned memories stop being found by their words too
+    memories.remove(1);
+    index.sync(&memories);
+    assert_eq!(recalled(&index, "college", &config), vec!(0, 1));
+    let loaded = memory::MemoryIndex::from_json(&index.to_json(), &memories).expect("Unable to load the index!");
+    assert_eq!(recalled(&loaded, "4127", &config), vec!(0, 2, 1));
+}
+
+#[tokio::test]
+async fn chunk_conversations() -> Result<(), ()> {
+    let lines: Vec<String> = (0..10).map(|turn| format!("user: Line {} about topic{}", turn, turn)).collect();
+    let conversation = lines.join("\n");
+
+    // Windows of a few turns, overlapping by one
+    let excerpts: Vec<&str> = memory::chunk_ranges(&conversation).into_iter().map(|range| &conversation[range]).collect();
+    assert_eq!(excerpts, vec!(lines[0..4].join("\n"), lines[3..7].join("\n"), lines[6..10].join("\n")));
+
+    // Lines too long to embed are split, without splitting any characters
+    let long_line = "é".repeat(memory::MAX_CHUNK_BYTES);
+    let ranges = memory::chunk_ranges(&long_line);
+    assert_eq!(ranges.len(), 2);
+    assert!(ranges.iter().all(|range| range.len() <= memory::MAX_CHUNK_BYTES && long_line.is_char_boundary(range.start)));
+    assert!(memory::chunk_ranges("\n\n").is_empty());
+
+    // Each chunk is embedded, and recalled by itself
+    let server = MockServer::start(Script::default());
+    let memory = memory::Memory::new(conversation.clone(), &server.providers()).await
+        .map_err(|_| ())?;
+    assert_eq!(memory.chunks.len(), 3);
+    assert_eq!(memory.chunks[1].embedding, memory::Embedding::from(mock::mock_embedding(memory.excerpt(Some(1)))));
+    assert_eq!(memory.excerpt(None), conversation);
+
+    let mut monikai = monikai::Monikai {
+        memories: vec!(memory),
+        retrieval: config::RetrievalConfig { min_similarity: 0.1, ..config::RetrievalConfig::default() },
+        ..monikai::Monikai::default()
+    };
+    let recalled = monikai.recall("topic8", &mock::mock_embedding("topic8"));
+    assert_eq!(recalled.len(), 1);
+    assert_eq!(recalled[0].2, Some(2));
+    assert!(!monikai.memories[0].excerpt(recalled[0].2).contains("topic1"));
+
+    Ok(())
+}
+
+// Memories with random, but repeatable, embeddings.
+fn random_memories( count: usize, dimensions: usize, seed: u64 ) -> Vec<memory::Memory> {
+    let mut state = seed;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        (state % 2000) as f64 / 1000f64 - 1f64
+    };
+
+    (0..count)
+        .map(|timestamp| memory::Memory {
+            id: memory::MemoryId::new(),
+            embedding: memory::Embedding::from((0..dimensions).map(|_| random()).collect::<Vec<f64>>()),
+            embedding_model: String::from("text-embedding-ada-002"),
+            user_profile: String::new(),
+            interaction_summary: String::new(),
+            conversation: format!("Conversation #{}", timestamp),
+            timestamp: timestamp as u64,
+            times_read: 0usize,
+            ..memory::Memory::default()
+        })
+        .collect()
+}
+
+#[test]
+fn approximate_recall() {
+    let memories = random_memories(memory::EXACT_SCAN_LIMIT * 2, 32, 42);
+    let queries = random_memories(20, 32, 7);
+    let index = memory::MemoryIndex::build(&memories);
+    let config = config::RetrievalConfig { top_k: 10, min_similarity: -1., ..config::RetrievalConfig::default() };
+
+    // The graph should find nearly all of the true nearest memories
+    let mut found = 0;
+    for query in &queries {
+        let mut exact: Vec<(usize, f64)> = memories.iter()
+            .enumerate()
+            .map(|(position, memory)| (position, linalg::dot_product_f32(&query.embedding, &memory.embedding).unwrap() as f64))
+            .collect();
+        exact.sort_by(|(_, a_sim), (_, b_sim)| b_sim.total_cmp(a_sim));
+        exact.truncate(10);
+
+        let recalled = index.search(&query.embedding, &config);
+        assert_eq!(recalled.len(), 10);
+        found += recalled.iter()
+            .filter(|(position, _)| exact.iter().any(|(exact_position, _)| exact_position == position))
+            .count();
+    }
+    assert!(found >= 20 * 10 * 9 / 10, "Only found {} of the 200 nearest memories", found);
+}
+
+#[test]
+fn sync_and_persist_index() {
+    let mut memories = random_memories(memory::EXACT_SCAN_LIMIT + 100, 16, 3);
+    let mut index = memory::MemoryIndex::build(&memories);
+    let config = config::RetrievalConfig { top_k: 1, min_similarity: 0.99, ..config::RetrievalConfig::default() };
+
+    // Prune every third memory and make a few new ones
+    let mut position = 0;
+    memories.retain(|_| { position += 1; position % 3 != 0 });
+    memories.extend(random_memories(50, 16, 5).into_iter().map(|mut memory| { memory.timestamp += 10_000; memory }));
+    index.sync(&memories);
+
+    // Every memory is still found by its own embedding, at its new position
+    for (position, memory) in memories.iter().enumerate() {
+        assert_eq!(index.search(&memory.embedding, &config).first().map(|(found, _)| *found), Some(position));
+    }
+
+    let index_json = index.to_json();
+    let loaded = memory::MemoryIndex::from_json(&index_json, &memories).expect("Unable to load the index!");
+    assert_eq!(loaded.search(&memories[7].embedding, &config), index.search(&memories[7].embedding, &config));
+
+    // But not for different memories
+    assert!(memory::MemoryIndex::from_json(&index_json, &memories[1..]).is_none());
+}
+
+#[test]
+fn migrate_embedding_storage() -> Result<(), ()> {
+    // Older files store each embedding as a plain list of numbers
+    let legacy_json = std::fs::read_to_string("data/monikai.json")
+        .map_err(|_| ())?;
+    let monikai: monikai::Monikai = serde_json::from_str(&legacy_json)
+        .map_err(|_| ())?;
+
+    let migrated_json = serde_json::to_string_pretty(&monikai)
+        .map_err(|_| ())?;
+    let migrated: monikai::Monikai = serde_json::from_str(&migrated_json)
+        .map_err(|_| ())?;
+    assert!(migrated_json.len() < legacy_json.len());
+    for (original, migrated) in monikai.memories.iter().zip(migrated.memories.iter()) {
+        assert_eq!(original.embedding, migrated.embedding);
+    }
+
+    // Legacy embeddings are normalized on the way in
+    let embedding: memory::Embedding = serde_json::from_str("[3.0, 4.0]")
+        .map_err(|_| ())?;
+    assert_eq!(&*embedding, &[0.6f32, 0.8f32]);
+    assert_eq!(serde_json::to_string(&embedding).map_err(|_| ())?, "\"mpkZP83MTD8=\"");
+
+    assert!(serde_json::from_str::<memory::Embedding>("\"AAAA\"").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn storage_backends() {
+    let directory = std::env::temp_dir().join(format!("monikai-storage-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let backends: Vec<Box<dyn storage::Storage>> = vec!(
+        Box::new(storage::JsonStorage::new(&directory.join("monikai.json"))),
+        Box::new(storage::SqliteStorage::open(&directory.join("monikai.db")).unwrap())
+    );
+
+    for mut storage in backends {
+        assert!(matches!(storage.load(), Err(storage::StorageError::Missing(_))));
+
+        let mut monikai = monikai::Monikai {
+            current_conversation: vec!(llm::Message { role: String::from("user"), content: String::from("Hi!") }),
+            ..character(llm::Providers::default(), random_memories(5, 8, 11))
+        };
+        monikai.memories[2].user_profile = String::from("The MC likes 100% dark chocolate.");
+        monikai.memories[2].channels = vec!(memory::Channel::Repl);
+        monikai.memories[2].participants = vec!(String::from("user"), String::from("assistant"));
+        monikai.memories[2].chunks = vec!(memory::Chunk { start: 0, end: 1, embedding: memory::Embedding::from(vec![0., 1.]) });
+        monikai.conversation_channels = vec!(memory::Channel::Websocket);
+        let source = monikai.memories[2].id;
+        let fact = |subject: &str, object: &str, timestamp: u64| knowledge::Fact {
+            id: knowledge::FactId::new(),
+            subject: subject.to_string(),
+            predicate: String::from("is named"),
+            object: object.to_string(),
+            confidence: 0.8,
+            sources: vec!(source),
+            timestamp
+        };
+        monikai.knowledge.facts = vec!(fact("MC's sister", "Yuri", 3), fact("MC's cat", "Natsuki", 3));
+        monikai.index.sync(&monikai.memories);
+        storage.save(&monikai).unwrap();
+
+        // Reading, pruning and ending a conversation are all picked up by the next save
+        monikai.memories[0].times_read += 3;
+        monikai.memories[0].last_accessed = Some(42);
+        monikai.memories[0].tags.push(String::from("chocolate"));
+        monikai.memories.remove(1);
+        let mut new_memory = random_memories(1, 8, 12).remove(0);
+        new_memory.timestamp = 100;
+        monikai.memories.push(new_memory);
+        monikai.current_conversation.push(llm::Message { role: String::from("assistant"), content: String::from("Hello!") });
+        monikai.last_spoken_to = 42;
+        let (sister, cat) = (monikai.knowledge.facts[0].id, monikai.knowledge.facts[1].id);
+        monikai.edit_fact(sister, "MC's sister", "is named", "Sayori");
+        monikai.unlearn(cat);
+        monikai.knowledge.facts.push(fact("MC's dog", "Monika", 100));
+        monikai.index.sync(&monikai.memories);
+        storage.save(&monikai).unwrap();
+
+        let loaded = storage.load().unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), serde_json::to_value(&monikai).unwrap());
+        assert_eq!(loaded.index.to_json(), monikai.index.to_json());
+
+        // A conversation started over is saved in place of the last, even once it's grown as long
+        monikai.current_conversation = vec!(
+            llm::Message { role: String::from("user"), content: String::from("Back again!") },
+            llm::Message { role: String::from("assistant"), content: String::from("Welcome back!") },
+            llm::Message { role: String::from("user"), content: String::from("Thanks!") });
+        storage.save(&monikai).unwrap();
+        let contents: Vec<String> = storage.load().unwrap().current_conversation.into_iter().map(|message| message.content).collect();
+        assert_eq!(contents, vec!("Back again!", "Welcome back!", "Thanks!"));
+
+        // A cleared conversation stays cleared
+        monikai.current_conversation.clear();
+        storage.save(&monikai).unwrap();
+        assert!(storage.load().unwrap().current_conversation.is_empty());
+
+        let timestamps: Vec<u64> = storage.memories_between(1, 3).unwrap().iter().map(|memory| memory.timestamp).collect();
+        assert_eq!(timestamps, vec!(2, 3));
+        let found = storage.search_memories("100% DARK").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].conversation, "Conversation #2");
+        assert!(storage.search_memories("100%_dark").unwrap().is_empty());
+    }
+
+    std::fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn recover_from_corrupt_save() {
+    let directory = std::env::temp_dir().join(format!("monikai-backups-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("monikai.json");
+    let mut storage = storage::JsonStorage::new(&path)
+        .with_backups(2, std::time::Duration::ZERO);
+    let mut monikai = character(llm::Providers::default(), random_memories(3, 8, 21));
+
+    for last_spoken_to in 1..=3 {
+        monikai.last_spoken_to = last_spoken_to;
+        storage::Storage::save(&mut storage, &monikai).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    // Only the newest backups are kept, and nothing is left half-written
+    let files: Vec<String> = std::fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert!(files.iter().all(|file| !file.ends_with(".tmp")));
+    assert_eq!(std::fs::read_dir(directory.join("backups")).unwrap().count(), 2);
+
+    // A save cut short is recovered from the newest backup
+    std::fs::write(&path, "{ \"description\": \"You're Mon").unwrap();
+    assert!(matches!(storage::Storage::load(&mut storage), Err(storage::StorageError::Parse(_))));
+
+    let (recovered, _) = storage::Storage::recover(&mut storage).expect("Unable to recover!");
+    assert_eq!(recovered.last_spoken_to, 3);
+    assert_eq!(recovered.memories.len(), 3);
+
+    // A backup that can't be made doesn't stop the save, or the index saved beside it
+    std::fs::remove_dir_all(directory.join("backups")).unwrap();
+    std::fs::write(directory.join("backups"), "").unwrap();
+    monikai.index.sync(&monikai.memories);
+    storage::Storage::save(&mut storage, &monikai).unwrap();
+    assert!(storage::Storage::load(&mut storage).is_ok());
+    assert!(directory.join("monikai.index.json").exists());
+
+    std::fs::remove_dir_all(&directory).ok();
+}
+
+#[test]
+fn migrate_old_characters() {
+    let directory = std::env::temp_dir().join(format!("monikai-migrations-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let embedding = serde_json::to_value(memory::Embedding::from(vec![1., 0.])).unwrap();
+
+    // From before there was a schema version
+    let path = directory.join("monikai.json");
+    let unversioned = serde_json::json!({
+        "description": "You're Monika from DDLC.",
+        "memories": [{
+            "embedding": embedding,
+            "user_profile": "The MC says hi.",
+            "interaction_summary": "",
+            "conversation": "Hi!",
+            "timestamp": 1,
+            "times_read": 0
+        }],
+        "current_conversation": [],
+        "last_spoken_to": 0
+    }).to_string();
+    std::fs::write(&path, &unversioned).unwrap();
+
+    let mut json_storage = storage::JsonStorage::new(&path);
+    assert_eq!(json_storage.migrate(true).unwrap().len(), 6);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), unversioned);
+
+    assert_eq!(json_storage.migrate(false).unwrap().len(), 6);
+    assert!(json_storage.migrate(false).unwrap().is_empty());
+    assert_eq!(std::fs::read_to_string(directory.join("backups/monikai-schema-0.json")).unwrap(), unversioned);
+    let monikai = json_storage.load().unwrap();
+    assert_eq!(monikai.schema_version, storage::migrations::SCHEMA_VERSION);
+    assert_eq!(monikai.memories[0].embedding_model, storage::migrations::LEGACY_EMBEDDING_MODEL);
+    assert!(!monikai.memories[0].id.is_nil());
+    assert_eq!(monikai.profile.facts[0].text, "The MC says hi.");
+    assert_eq!(monikai.profile.facts[0].sources, vec!(monikai.memories[0].id));
+
+    // Newer files aren't read, rather than losing whatever they added
+    std::fs::write(&path, serde_json::json!({ "schema_version": storage::migrations::SCHEMA_VERSION + 1 }).to_string()).unwrap();
+    assert!(matches!(json_storage.migrate(true), Err(storage::StorageError::Schema(_))));
+    assert!(matches!(json_storage.load(), Err(storage::StorageError::Schema(_))));
+
+    // A database from before memories recorded their embedding model
+    let database_path = directory.join("monikai.db");
+    let connection = rusqlite::Connection::open(&database_path).unwrap();
+    connection.execute_batch("
+        CREATE TABLE character (id INTEGER PRIMARY KEY CHECK (id = 0), description TEXT NOT NULL, last_spoken_to INTEGER NOT NULL, memory_index TEXT);
+        CREATE TABLE memories (id INTEGER PRIMARY KEY, key INTEGER NOT NULL UNIQUE, timestamp INTEGER NOT NULL, conversation TEXT NOT NULL,
+            user_profile TEXT NOT NULL, interaction_summary TEXT NOT NULL, embedding BLOB NOT NULL, times_read INTEGER NOT NULL);
+        CREATE TABLE conversation (position INTEGER PRIMARY KEY, role TEXT NOT NULL, content TEXT NOT NULL);
+        INSERT INTO character (id, description, last_spoken_to) VALUES (0, 'You''re Monika from DDLC.', 0);
+    ").unwrap();
+    connection.execute(
+        "INSERT INTO memories (key, timestamp, conversation, user_profile, interaction_summary, embedding, times_read) VALUES (1, 1, 'Hi!', 'The MC says hi.', '', ?1, 0)",
+        rusqlite::params![memory::Embedding::from(vec![1., 0.]).to_bytes()]).unwrap();
+    drop(connection);
+
+    let mut sqlite_storage = storage::SqliteStorage::open(&database_path).unwrap();
+    assert_eq!(sqlite_storage.migrate(true).unwrap().len(), 6);
+    assert_eq!(sqlite_storage.migrate(false).unwrap().len(), 6);
+    assert!(sqlite_storage.migrate(false).unwrap().is_empty());
+    assert!(directory.join("backups/monikai-schema-0.db").exists());
+    let mut monikai = sqlite_storage.load().unwrap();
+    let memory = monikai.memories.remove(0);
+    assert_eq!(memory.embedding_model, storage::migrations::LEGACY_EMBEDDING_MODEL);
+    assert_eq!(memory.id.timestamp_ms(), 1000);
+    assert_eq!(monikai.profile.facts[0].sources, vec!(memory.id));
+
+    std::fs::remove_dir_all(&directory).ok();
+}
+
+// Sends a request to the backend, returning the status and any JSON body.
+async fn request( router: &axum::Router, method: &str, uri: &str, body: Option<serde_json::Value> ) -> (axum::http::StatusCode, serde_json::Value) {
+    use tower::ServiceExt;
+
+    let request = axum::http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json")
+        .body(axum::body::Body::from(body.map(|body| body.to_string()).unwrap_or_default()))
+        .unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    (status, serde_json::from_slice(&bytes).unwrap_or_default())
+}
+
+#[tokio::test]
+async fn address_memories_by_id() {
+    let server = MockServer::start(Script::default()
+        .chat("recommend a book", "Try 'Dune'!")
+        .completion("USER PROFILE:", "The MC likes science fiction.")
+        .completion("INTERACTION SUMMARY:", "We talked about Dune."));
+    let mut monikai = monikai::Monikai { providers: server.providers(), ..monikai::Monikai::default() };
+
+    monikai.send_message(String::from("Can you recommend a book?"), memory::Channel::Websocket, None).await.unwrap();
+    let id = monikai.end_conversation().await.unwrap();
+    let memory = monikai.memory(id).unwrap();
+    assert_eq!(memory.channels, vec!(memory::Channel::Websocket));
+    assert_eq!(memory.participants, vec!(String::from("user"), String::from("assistant")));
+    assert!(monikai.conversation_channels.is_empty());
+
+    let monikai = Arc::new(Mutex::new(monikai));
+    let router = monikai::monikai_router(monikai.clone(), monikai::Shutdown::default());
+
+    let (status, memories) = request(&router, "GET", "/memories", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(memories[0]["id"], id.to_string());
+    assert_eq!(memories[0]["interaction_summary"], "We talked about Dune.");
+
+    let (status, memory) = request(&router, "POST", &format!("/memories/{}/tags", id), Some(serde_json::json!(["books", "books", "sci-fi"]))).await;
+    assert_eq!(status, 200);
+    assert_eq!(memory["tags"], serde_json::json!(["books", "sci-fi"]));
+
+    assert_eq!(request(&router, "DELETE", &format!("/memories/{}", id), None).await.0, 204);
+    assert_eq!(request(&router, "GET", &format!("/memories/{}", id), None).await.0, 404);
+    assert_eq!(request(&router, "GET", "/memories/not-an-id", None).await.0, 400);
+    assert!(monikai.lock().await.memories.is_empty());
+}
+
+// Counts saves instead of making them, failing each one if told to.
+struct CountingStorage {
+    saves: Arc<std::sync::atomic::AtomicUsize>,
+    failing: bool
+}
+impl storage::Storage for CountingStorage {
+    fn migrate( &mut self, _dry_run: bool ) -> Result<Vec<&'static storage::migrations::Migration>, storage::StorageError> {
+        Ok(Vec::new())
+    }
+    fn load( &mut self ) -> Result<monikai::Monikai, storage::StorageError> {
+        Err(storage::StorageError::Missing(self.location()))
+    }
+    fn save( &mut self, _monikai: &monikai::Monikai ) -> Result<(), storage::StorageError> {
+        self.saves.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        if self.failing {
+            return Err(storage::StorageError::Io(String::from("The disk is full")));
+        }
+        Ok(())
+    }
+    fn memories_between( &self, _from: u64, _to: u64 ) -> Result<Vec<memory::Memory>, storage::StorageError> {
+        Ok(Vec::new())
+    }
+    fn search_memories( &self, _text: &str ) -> Result<Vec<memory::Memory>, storage::StorageError> {
+        Ok(Vec::new())
+    }
+    fn location( &self ) -> String {
+        String::from("nowhere")
+    }
+}
+
+#[tokio::test]
+async fn autosave_only_on_change() {
+    let saves = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let saved = || saves.load(std::sync::atomic::Ordering::SeqCst);
+    let monikai = Arc::new(Mutex::new(monikai::Monikai::default()));
+    let timing = config::TimingConfig { autosave_secs: 60, save_debounce_ms: 50, ..config::TimingConfig::default() };
+
+    let (handle, requests) = storage::StorageHandle::new();
+    let autosave = tokio::spawn(monikai::monikai_autosave(
+        monikai.clone(), Box::new(CountingStorage { saves: saves.clone(), failing: false }), timing, requests ));
+
+    // Nothing changed, so nothing is written
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(saved(), 0);
+
+    // A burst of changes is saved once it settles
+    for _ in 0..3 {
+        monikai.lock().await.touch();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert_eq!(saved(), 1);
+
+    // Asking to save always does
+    handle.save().await.unwrap();
+    assert_eq!(saved(), 2);
+
+    // Shutting down flushes anything unsaved, and stops the task
+    monikai.lock().await.touch();
+    handle.shutdown().await;
+    assert_eq!(saved(), 3);
+    autosave.await.unwrap();
+    assert!(handle.save().await.is_err());
+}
+
+#[tokio::test]
+async fn autosave_backs_off_failed_saves() {
+    let saves = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let saved = || saves.load(std::sync::atomic::Ordering::SeqCst);
+    let monikai = Arc::new(Mutex::new(monikai::Monikai::default()));
+    let timing = config::TimingConfig { autosave_secs: 60, save_debounce_ms: 10, ..config::TimingConfig::default() };
+
+    let (handle, requests) = storage::StorageHandle::new();
+    let autosave = tokio::spawn(monikai::monikai_autosave(
+        monikai.clone(), Box::new(CountingStorage { saves: saves.clone(), failing: true }), timing, requests ));
+
+    // A failed save waits a second before trying again, rather than trying every tick
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    monikai.lock().await.touch();
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    assert_eq!(saved(), 1);
+    tokio::time::sleep(std::time::Duration::from_millis(800)).await;
+    assert_eq!(saved(), 2);
+
+    // Then waits twice as long, even through more changes
+    monikai.lock().await.touch();
+    tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    assert_eq!(saved(), 2);
+
+    handle.shutdown().await;
+    autosave.await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_ends_conversation() {
+    let server = MockServer::start(Script::default()
+        .completion("USER PROFILE:", "The MC is heading to bed.")
+        .completion("INTERACTION SUMMARY:", "We said goodnight."));
+    let saves = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let monikai = Arc::new(Mutex::new(monikai::Monikai {
+        current_conversation: vec!(llm::Message { role: String::from("user"), content: String::from("Goodnight!") }),
+        providers: server.providers(),
+        ..monikai::Monikai::default()
+    }));
+
+    let (handle, requests) = storage::StorageHandle::new();
+    let autosave = tokio::spawn(monikai::monikai_autosave(
+        monikai.clone(), Box::new(CountingStorage { saves: saves.clone(), failing: false }), config::TimingConfig::default(), requests ));
+
+    // Anything waiting on the shutdown, even from after it was triggered, stops waiting
+    let shutdown = monikai::Shutdown::default();
+    let waiting = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.wait().await }
+    });
+    shutdown.trigger();
+    waiting.await.unwrap();
+    shutdown.wait().await;
+
+    monikai::monikai_shutdown( monikai.clone(), handle, true ).await;
+    autosave.await.unwrap();
+
+    let monikai = monikai.lock().await;
+    assert!(monikai.current_conversation.is_empty());
+    assert_eq!(monikai.memories.len(), 1);
+    assert_eq!(monikai.memories[0].interaction_summary, "We said goodnight.");
+    assert_eq!(saves.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn forgetting_curve() {
+    let day = 86400u64;
+    let made = memory::Memory {
+        timestamp: 1_000 * day,
+        ..memory::Memory::default()
+    };
+    let at = |days: f64| made.timestamp + (days * day as f64) as u64;
+
+    // Never recalled, it lasts about a week
+    assert!(!made.is_forgotten(at(6.)));
+    assert!(made.is_forgotten(at(7.)));
+
+    // Recalling it again straight away barely helps, while waiting a few days helps a lot
+    let mut crammed = made.clone();
+    crammed.recall(at(0.01));
+    crammed.recall(at(0.02));
+    let mut spaced = made.clone();
+    spaced.recall(at(3.));
+    assert!(crammed.stability().0 < memory::INITIAL_STABILITY_DAYS * 1.1);
+    assert!(spaced.stability().0 > crammed.stability().0 * 2.);
+    assert!(crammed.is_forgotten(at(8.)));
+    assert!(!spaced.is_forgotten(at(8.)));
+
+    // It fades from the last recall, not from when it was made
+    assert_eq!(spaced.stability().1, at(3.));
+    assert!((spaced.retention(at(3.)) - 1.).abs() < 1e-12);
+
+    // Reads from before recalls were recorded still count for something
+    let mut read = made.clone();
+    read.times_read = 5;
+    assert!(read.stability().0 > made.stability().0);
+    assert!(!read.is_forgotten(at(8.)));
+
+    // Enough spaced recalls, and it's never forgotten
+    let mut learned = made.clone();
+    let mut days = 0f64;
+    while learned.stability().0 < memory::PERMANENT_STABILITY_DAYS {
+        days += learned.stability().0;
+        learned.recall(at(days));
+    }
+    assert!(!learned.is_forgotten(at(days + 100_000.)));
+
+    // Pruning forgets what was learned only from faded memories, as forgetting them by hand does
+    let fresh = memory::Memory { id: memory::MemoryId::new(), timestamp: at(7.), ..memory::Memory::default() };
+    let mut monikai = monikai::Monikai {
+        memories: vec!(made.clone(), fresh.clone()),
+        ..monikai::Monikai::default()
+    };
+    monikai.profile.facts.push(profile::Fact {
+        category: profile::Category::Like,
+        text: String::from("The MC likes tea."),
+        sources: vec!(made.id),
+        updated: made.timestamp
+    });
+    let tea = r#"[{"subject": "MC", "predicate": "likes", "object": "tea"}]"#;
+    monikai.knowledge = monikai.knowledge.learned(tea, &made).unwrap();
+    let version = monikai.version;
+
+    assert_eq!(monikai.prune(at(6.)), 0);
+    assert_eq!(monikai.version, version);
+    assert_eq!(monikai.prune(at(8.)), 1);
+    assert_eq!(monikai.memories.iter().map(|memory| memory.id).collect::<Vec<_>>(), vec!(fresh.id));
+    assert!(monikai.profile.facts.is_empty() && monikai.knowledge.facts.is_empty());
+    assert!(monikai.version > version);
+}
+
+#[test]
+fn cosine_similarity_known_values() {
+    let similarity = |a: &[f64], b: &[f64]| linalg::cosine_similarity(a, b).unwrap();
+
+    assert!((similarity(&[1., 0.], &[1., 0.]) - 1.).abs() < 1e-12);
+    assert!((similarity(&[1., 0.], &[0., 1.])).abs() < 1e-12);
+    assert!((similarity(&[1., 0.], &[-1., 0.]) + 1.).abs() < 1e-12);
+    assert!((similarity(&[1., 1.], &[1., 0.]) - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-12);
+    assert!((similarity(&[1., 2., 3.], &[4., 5., 6.]) - 32. / (14f64.sqrt() * 77f64.sqrt())).abs() < 1e-12);
+
+    assert_eq!(similarity(&[0., 0.], &[1., 2.]), 0.);
+    assert_eq!(similarity(&[f64::NAN, 1.], &[1., 2.]), 0.);
+    assert_eq!(similarity(&[f64::INFINITY, 1.], &[1., 2.]), 0.);
+    assert_eq!(similarity(&[], &[]), 0.);
+
+    assert_eq!(
+        linalg::cosine_similarity(&[1., 2.], &[1., 2., 3.]),
+        Err(linalg::LinalgError::DimensionMismatch { left: 2, right: 3 })
+    );
+}
+
+mod cosine_similarity_properties {
+    use proptest::prelude::*;
+    use crate::linalg;
+
+    fn vectors( dimensions: usize ) -> impl Strategy<Value = (Vec<f64>, Vec<f64>)> {
+        (
+            prop::collection::vec(-1e6f64..1e6, dimensions),
+            prop::collection::vec(-1e6f64..1e6, dimensions)
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn symmetric( (a, b) in (1usize..32).prop_flat_map(vectors) ) {
+            prop_assert_eq!(linalg::cosine_similarity(&a, &b), linalg::cosine_similarity(&b, &a));
+        }
+
+        #[test]
+        fn bounded( (a, b) in (1usize..32).prop_flat_map(vectors) ) {
+            let similarity = linalg::cosine_similarity(&a, &b).unwrap();
+            prop_assert!((-1f64..=1f64).contains(&similarity));
+        }
+
+        #[test]
+        fn scale_invariant( (a, b) in (1usize..32).prop_flat_map(vectors), scale in 1e-3f64..1e3 ) {
+            let scaled: Vec<f64> = a.iter().map(|element| element * scale).collect();
+
+            let similarity = linalg::cosine_similarity(&a, &b).unwrap();
+            let scaled_similarity = linalg::cosine_similarity(&scaled, &b).unwrap();
+            prop_assert!((similarity - scaled_similarity).abs() < 1e-9);
+        }
+
+        #[test]
+        fn self_similar( a in prop::collection::vec(-1e6f64..1e6, 1..32) ) {
+            prop_assume!(linalg::magnitude(&a) > 1e-9);
+            prop_assert!((linalg::cosine_similarity(&a, &a).unwrap() - 1f64).abs() < 1e-9);
+        }
+
+        #[test]
+        fn mismatched_dimensions( a in prop::collection::vec(-1e6f64..1e6, 0..32), b in prop::collection::vec(-1e6f64..1e6, 0..32) ) {
+            prop_assume!(a.len() != b.len());
+            prop_assert!(linalg::cosine_similarity(&a, &b).is_err());
+        }
+
+        #[test]
+        fn zero_vector_unrelated( a in prop::collection::vec(-1e6f64..1e6, 1..32) ) {
+            let zero = vec![0f64; a.len()];
+            prop_assert_eq!(linalg::cosine_similarity(&a, &zero), Ok(0f64));
+        }
+    }
+}
+
+#[tokio::test]
+async fn consolidate_profile() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("NEW OR CHANGED FACTS", r#"Sure! [
+            {"category": "dislike", "fact": "The MC has gone off tea.", "from": [1], "new": true},
+            {"category": "person", "fact": "The MC's sister is Yuri.", "from": [], "new": true},
+            {"category": "person", "fact": "the MC's sister is Yuri.", "new": true}
+        ]"#));
+    let (tea, kyoto) = (memory::MemoryId::new(), memory::MemoryId::new());
+    let mut monikai = monikai::Monikai {
+        profile: profile::Profile { facts: vec!(
+            profile::Fact { category: profile::Category::Like, text: String::from("The MC likes tea."), sources: vec!(tea), updated: 10 },
+            profile::Fact { category: profile::Category::Plan, text: String::from("The MC will visit Kyoto."), sources: vec!(kyoto), updated: 20 }
+        )},
+        current_conversation: vec!(llm::Message { role: String::from("user"), content: String::from("I can't stand tea anymore. My sister Yuri agrees.") }),
+        providers: server.providers(),
+        ..monikai::Monikai::default()
+    };
+
+    let id = monikai.end_conversation().await
+        .map_err(|_| ())?;
+    let prompt = server.requests("completions").await.last().unwrap().body.to_string();
+    assert!(prompt.contains("1. (like) The MC likes tea."));
+
+    // The newer fact replaces the one it contradicts, keeping where both came from, and duplicates are combined
+    let facts = &monikai.profile.facts;
+    assert_eq!(facts.len(), 3);
+    assert_eq!(facts[0].text, "The MC will visit Kyoto.");
+    assert_eq!((facts[1].category, facts[1].sources.clone()), (profile::Category::Dislike, vec!(tea, id)));
+    assert_eq!(facts[1].updated, monikai.memories[0].timestamp);
+    assert_eq!((facts[2].category, facts[2].sources.clone()), (profile::Category::Person, vec!(id)));
+
+    // Anything unreadable leaves the profile as it was
+    assert!(monikai.profile.merged("Nothing of note.", &monikai.memories[0]).is_none());
+
+    // Forgetting the memory forgets what only it taught
+    monikai.forget(id);
+    let facts = &monikai.profile.facts;
+    assert_eq!(facts.len(), 2);
+    assert_eq!(facts[1].sources, vec!(tea));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn extract_facts() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("List the FACTS", r#"[
+            {"subject": "MC's sister", "predicate": "is named", "object": "Yuri", "confidence": 0.9},
+            {"subject": "MC", "predicate": "lives in", "object": "Chicago", "confidence": 1.7},
+            {"subject": "mc's sister", "predicate": "IS NAMED", "object": "yuri", "confidence": 0.4},
+            {"subject": "MC", "predicate": "owns", "object": " "}
+        ]"#));
+    let mut monikai = monikai::Monikai {
+        current_conversation: vec!(llm::Message { role: String::from("user"), content: String::from("My sister Yuri and I live in Chicago.") }),
+        providers: server.providers(),
+        ..monikai::Monikai::default()
+    };
+
+    // Duplicates are learned once, with the higher confidence, and incomplete facts not at all
+    let id = monikai.end_conversation().await
+        .map_err(|_| ())?;
+    let facts = &monikai.knowledge.facts;
+    assert_eq!(facts.len(), 2);
+    assert_eq!((facts[0].to_string(), facts[0].confidence, facts[0].sources.clone()), (String::from("MC's sister is named Yuri"), 0.9, vec!(id)));
+    assert_eq!(facts[1].confidence, 1.);
+    assert!(monikai.knowledge.learned("Nothing of note.", &monikai.memories[0]).is_none());
+    let (sister, chicago) = (facts[0].id, facts[1].id);
+
+    // If the profile can't be merged, nothing is kept, so ending the conversation can be retried
+    let failing_server = MockServer::start(Script::default()
+        .completion("List the FACTS", r#"[{"subject": "MC", "predicate": "likes", "object": "tea"}]"#)
+        .fail_completion("NEW OR CHANGED FACTS"));
+    let mut failing = monikai::Monikai {
+        current_conversation: vec!(llm::Message { role: String::from("user"), content: String::from("I like tea.") }),
+        providers: failing_server.providers(),
+        ..monikai::Monikai::default()
+    };
+    assert!(failing.end_conversation().await.is_err());
+    assert!(failing.knowledge.facts.is_empty() && failing.memories.is_empty());
+    assert_eq!(failing.current_conversation.len(), 1);
+
+    // Only what's asked about is added to the prompt
+    monikai.send_message(String::from("What's my sister's name?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+    let prompt = server.requests("chat/completions").await.last().unwrap().body.to_string();
+    assert!(prompt.contains("- MC's sister is named Yuri"));
+    assert!(!prompt.contains("Chicago"));
+
+    // Facts can be looked up, corrected and removed by ID
+    let monikai = Arc::new(Mutex::new(monikai));
+    let router = monikai::monikai_router(monikai.clone(), monikai::Shutdown::default());
+
+    let (status, found) = request(&router, "GET", "/facts?about=SISTER", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(found.as_array().map(Vec::len), Some(1));
+    let (status, edited) = request(&router, "PUT", &format!("/facts/{}", sister), Some(serde_json::json!({ "subject": "MC's sister", "predicate": "is named", "object": "Sayori" }))).await;
+    assert_eq!(status, 200);
+    assert_eq!((edited["object"].as_str(), edited["confidence"].as_f64()), (Some("Sayori"), Some(1.)));
+    assert_eq!(edited["sources"], serde_json::json!([]));
+    assert_eq!(request(&router, "PUT", &format!("/facts/{}", sister), Some(serde_json::json!({ "subject": "", "predicate": "is", "object": "?" }))).await.0, 422);
+    assert_eq!(request(&router, "DELETE", &format!("/facts/{}", chicago), None).await.0, 204);
+    assert_eq!(request(&router, "GET", &format!("/facts/{}", chicago), None).await.0, 404);
+
+    // Facts set by hand rest on no memory, so relearning them changes nothing, and forgetting memories keeps them
+    let mut monikai = monikai.lock().await;
+    let other = memory::Memory { id: memory::MemoryId::new(), timestamp: 5, ..memory::Memory::default() };
+    let sayori = r#"[{"subject": "mc's sister", "predicate": "is named", "object": "Sayori", "confidence": 0.6}]"#;
+    monikai.knowledge = monikai.knowledge.learned(sayori, &other).unwrap();
+    assert_eq!(monikai.knowledge.facts.len(), 1);
+    assert!(monikai.knowledge.facts[0].sources.is_empty());
+    monikai.forget(id);
+    monikai.knowledge.forget(other.id);
+    assert_eq!(monikai.knowledge.facts.len(), 1);
+
+    // Learned facts are forgotten along with the memories they came from, once there are none left
+    let third = memory::Memory { id: memory::MemoryId::new(), timestamp: 6, ..memory::Memory::default() };
+    let tea = r#"[{"subject": "MC", "predicate": "likes", "object": "tea", "confidence": 0.6}]"#;
+    monikai.knowledge = monikai.knowledge.learned(tea, &other).unwrap()
+        .learned(tea, &third).unwrap();
+    assert_eq!(monikai.knowledge.facts[1].sources, vec!(other.id, third.id));
+    monikai.knowledge.forget(other.id);
+    assert_eq!(monikai.knowledge.facts[1].sources, vec!(third.id));
+    monikai.knowledge.forget(third.id);
+    assert_eq!(monikai.knowledge.facts.len(), 1);
+
+    // Editing a fact into one already known leaves just the edited one
+    monikai.knowledge = monikai.knowledge.learned(tea, &third).unwrap();
+    assert_eq!(monikai.knowledge.facts.len(), 2);
+    let edited = monikai.edit_fact(sister, "MC", "Likes", "Tea").map(|fact| fact.id);
+    assert_eq!(edited, Some(sister));
+    assert_eq!(monikai.knowledge.facts.len(), 1);
+    assert_eq!(monikai.knowledge.facts[0].to_string(), "MC Likes Tea");
+    assert!(monikai.knowledge.facts[0].set_by_hand());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn budgeted_prompt() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("SUMMARY of the conversation", "We compared notes on forty different novels."));
+    let prompt_config = config::PromptConfig { context_tokens: Some(1000), response_tokens: 200, ..config::PromptConfig::default() };
+    let mut monikai = monikai::Monikai {
+        profile: profile::Profile {
+            facts: (0..50).map(|day| profile::Fact {
+                category: profile::Category::Like,
+                text: format!("On day {} I learned the MC is reading a long series of books.", day),
+                sources: vec!(memory::MemoryId::new()),
+                updated: day
+            }).collect()
+        },
+        current_conversation: (0..40).map(|turn| llm::Message {
+            role: String::from(if turn % 2 == 0 { "user" } else { "assistant" }),
+            content: format!("Message {} is about novel number {} and what happens in it.", turn, turn / 2)
+        }).collect(),
+        prompt: prompt_config.clone(),
+        ..character(llm::Providers::from_single(server.openai(&server.config())
+            .with_response_tokens(prompt_config.response_tokens)), Vec::new())
+    };
+
+    let tokenizer = prompt::Tokenizer::for_model("gpt-3.5-turbo");
+    let prompt_tokens = |request: &mock::RecordedRequest| -> usize {
+        serde_json::from_value::<Vec<llm::Message>>(request.body["messages"].clone()).unwrap()
+            .iter()
+            .map(|message| tokenizer.count_message(message))
+            .sum()
+    };
+
+    monikai.send_message(String::from("Which was your favourite?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+    monikai.send_message(String::from("And the second?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+
+    // Everything fits, leaving room for a response no longer than asked for,
+    // with the start of the conversation summarized, only once
+    let chat_requests = server.requests("chat/completions").await;
+    for request in &chat_requests {
+        assert!(prompt_tokens(request) <= 1000 - 200);
+        assert_eq!(request.body["max_tokens"], 200);
+        assert_eq!(request.body["temperature"], 1.0);
+    }
+    let prompt = chat_requests[1].body.to_string();
+    assert!(prompt.contains("Earlier in this conversation: We compared notes on forty different novels."));
+    assert!(prompt.contains("And the second?"));
+    assert!(!prompt.contains("Message 0 is about"));
+    assert!(prompt.contains("Message 39 is about"));
+
+    // Keeping the most recent of what's been learned
+    assert!(prompt.contains("On day 49 I learned"));
+    assert!(!prompt.contains("On day 0 I learned"));
+
+    let summaries = server.requests("completions").await
+        .into_iter()
+        .filter(|request| request.body.to_string().contains("SUMMARY of the conversation"))
+        .count();
+    assert_eq!(summaries, 1);
+
+    // And starts over with the next conversation
+    monikai.end_conversation().await
+        .map_err(|_| ())?;
+    assert_eq!(monikai.conversation_summary, (0, String::new()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn summarize_within_completion_context() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .completion("SUMMARY of the conversation", "We went through a lot of novels."));
+    let mut monikai = monikai::Monikai {
+        current_conversation: (0..200).map(|turn| llm::Message {
+            role: String::from(if turn % 2 == 0 { "user" } else { "assistant" }),
+            content: format!("Message {} is about novel number {}, which has a long and winding plot full of twists.", turn, turn / 2)
+        }).collect(),
+        providers: server.providers(),
+        // The chat model takes far more at once than the completion model summarizing for it
+        prompt: config::PromptConfig { context_tokens: Some(6000), completion_context_tokens: Some(1024), ..config::PromptConfig::default() },
+        ..monikai::Monikai::default()
+    };
+
+    monikai.send_message(String::from("Which was your favourite?"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+
+    // So it's summarized in batches, each fitting alongside the completion
+    let tokenizer = prompt::Tokenizer::for_model(&server.config().completion_model);
+    let summaries: Vec<String> = server.requests("completions").await
+        .into_iter()
+        .filter_map(|request| request.body["prompt"].as_str().map(str::to_string))
+        .filter(|prompt| prompt.contains("SUMMARY of the conversation"))
+        .collect();
+    assert!(summaries.len() > 1);
+    for summary in &summaries {
+        assert!(tokenizer.count(summary) <= 1024 - server.config().max_tokens);
+    }
+    assert!(summaries[0].contains("Message 0 is about"));
+    assert!(summaries[1].contains("We went through a lot of novels."));
+
+    // Up to where the conversation starts
+    let first_turn = monikai.conversation_summary.0;
+    assert!(first_turn > 0);
+    assert!(summaries.last().unwrap().contains(&format!("Message {} is about", first_turn - 1)));
+
+    // And a turn too long to fit keeps its end
+    let tail = tokenizer.tail("Once upon a time, there was a poem.", 4);
+    assert!(!tail.is_empty() && tokenizer.count(&tail) <= 4);
+    assert!("Once upon a time, there was a poem.".ends_with(&tail));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn stream_response() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .chat("favorite poem", "I think it has to be 'The Road Not Taken' by Robert Frost."));
+    let mut monikai = character(server.providers(), Vec::new());
+
+    let (deltas, mut delta_receiver) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let response = monikai.send_message(String::from("What's your favorite poem?"), memory::Channel::Repl, Some(deltas)).await
+        .map_err(|_| ())?;
+
+    let mut streamed = Vec::new();
+    while let Some(delta) = delta_receiver.recv().await {
+        streamed.push(delta);
+    }
+
+    assert!(streamed.len() > 1);
+    assert_eq!(streamed.concat(), response);
+    assert_eq!(response, "I think it has to be 'The Road Not Taken' by Robert Frost.");
+    assert_eq!(monikai.current_conversation.last().map(|message| message.content.clone()), Some(response));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recover_from_provider_errors() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .fail("chat/completions", 429, 1)
+        .fail("embeddings", 500, 1));
+    let no_retries = server.openai(&config::OpenAiConfig {
+        max_retries: 0,
+        ..server.config()
+    });
+    let mut monikai = character(llm::Providers::from_single(no_retries), Vec::new());
+
+    // A rate limit is reported, and the message is not kept
+    let result = monikai.send_message(String::from("Hello!"), memory::Channel::Repl, None).await;
+    assert!(matches!(result, Err(llm::Error::RateLimited { .. })));
+    assert!(monikai.current_conversation.is_empty());
+
+    // The next attempt goes through
+    monikai.send_message(String::from("Hello!"), memory::Channel::Repl, None).await
+        .map_err(|_| ())?;
+    assert_eq!(monikai.current_conversation.len(), 2);
+
+    // A failed encoding leaves the conversation to be retried
+    let result = monikai.end_conversation().await;
+    assert!(matches!(result, Err(llm::Error::Http { status: 500, .. })));
+    assert_eq!(monikai.current_conversation.len(), 2);
+    assert!(monikai.memories.is_empty());
+
+    monikai.end_conversation().await
+        .map_err(|_| ())?;
+    assert_eq!(monikai.memories.len(), 1);
+
+    // The official API can't be used without a key
+    let keyless = openai::OpenAi::from_config(&config::OpenAiConfig::default());
+    let result = llm::EmbeddingProvider::embed(&keyless, "Hello!").await;
+    assert!(matches!(result, Err(llm::Error::MissingApiKey)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_transient_errors() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .fail("chat/completions", 429, 2)
+        .fail("embeddings", 503, 1)
+        .fail("completions", 400, 1));
+    let providers = server.providers();
+
+    // Rate limits and server errors are retried until they go through
+    providers.chat.chat(vec![llm::Message { role: String::from("user"), content: String::from("Hello!") }]).await
+        .map_err(|_| ())?;
+    assert_eq!(server.requests("chat/completions").await.len(), 3);
+
+    providers.embedding.embed("Hello!").await
+        .map_err(|_| ())?;
+    assert_eq!(server.requests("embeddings").await.len(), 2);
+
+    // Client errors are not
+    let result = providers.completion.complete(String::from("Hello!")).await;
+    assert!(matches!(result, Err(llm::Error::Http { status: 400, .. })));
+    assert_eq!(server.requests("completions").await.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cap_retry_after() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .fail("chat/completions", 429, 1)
+        .retry_after(std::time::Duration::from_secs(3600)));
+    let mut openai = server.openai(&server.config());
+    openai.retry_policy.max_delay = std::time::Duration::from_millis(50);
+
+    // However long the server asks for, the retry waits no longer than the policy allows
+    let started = std::time::Instant::now();
+    llm::ChatProvider::chat(&openai, vec![llm::Message { role: String::from("user"), content: String::from("Hello!") }]).await
+        .map_err(|_| ())?;
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert_eq!(server.requests("chat/completions").await.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent_requests() -> Result<(), ()> {
+    let server = MockServer::start(Script::default()
+        .latency(std::time::Duration::from_millis(300)));
+    let providers = llm::Providers::from_single(server.openai(&config::OpenAiConfig {
+        max_concurrent_requests: 3,
+        ..server.config()
+    }));
+
+    // The embedding, profile and summary are generated side by side, not one after another
+    let started = std::time::Instant::now();
+    memory::Memory::new(String::from("MC: Hello!"), &providers).await
+        .map_err(|_| ())?;
+
+    assert!(started.elapsed() < std::time::Duration::from_millis(800));
+
+    Ok(())
+}
+
+#[test]
+fn layer_config() -> Result<(), ()> {
+    let mut config: config::Config = toml::from_str("
+        [openai]
+        chat_model = \"gpt-4\"
+
+        [timing]
+        autosave_secs = 30
+    ").map_err(|_| ())?;
+
+    // Anything not in the file keeps its default
+    assert_eq!(config.openai.chat_model, "gpt-4");
+    assert_eq!(config.openai.embedding_model, "text-embedding-ada-002");
+    assert_eq!(config.timing.autosave_secs, 30);
+    assert_eq!(config.timing.conversation_timeout_secs, 300);
+    assert_eq!(config.server.bind, "0.0.0.0:3000");
+
+    // The environment overrides the file
+    config.apply_env(|name| match name {
+        "MONIKAI_CHAT_MODEL" => Some(String::from("llama3")),
+        "MONIKAI_BIND" => Some(String::from("127.0.0.1:8080")),
+        "OPENAI_BASE_URL" => Some(String::from("http://localhost:11434/v1")),
+        _ => None
+    }).map_err(|_| ())?;
+    assert_eq!(config.openai.chat_model, "llama3");
+    assert_eq!(config.server.bind, "127.0.0.1:8080");
+    assert_eq!(config.openai.base_url, "http://localhost:11434/v1");
+
+    // Malformed numbers are rejected rather than ignored
+    let result = config.apply_env(|name| (name == "MONIKAI_AUTOSAVE_SECS").then(|| String::from("soon")));
+    assert!(matches!(result, Err(config::ConfigError::InvalidEnv { .. })));
+
+    // And flags override everything
+    let cli = <cli::Cli as clap::Parser>::try_parse_from(["monikaiv2", "--chat-model", "gpt-4o", "--character", "data/other.json"])
+        .map_err(|_| ())?;
+    cli.apply(&mut config);
+    assert_eq!(config.openai.chat_model, "gpt-4o");
+    assert_eq!(config.storage.character_path, std::path::PathBuf::from("data/other.json"));
+
+    Ok(())
+}
+
+#[test]
+fn import_and_inspect() -> Result<(), ()> {
+    let character_json_str = std::fs::read_to_string("data/monikai.json")
+        .map_err(|_| ())?;
+    let mut monikai: monikai::Monikai = serde_json::from_str(&character_json_str)
+        .map_err(|_| ())?;
+    let original_count = monikai.memories.len();
+
+    // Merging a character into itself adds nothing
+    let duplicates = monikai.memories.clone();
+    assert_eq!(monikai.merge_memories(duplicates), 0);
+
+    // But new memories are kept
+    let mut new_memory = monikai.memories[0].clone();
+    new_memory.timestamp += 1;
+    assert_eq!(monikai.merge_memories(vec![new_memory]), 1);
+    assert_eq!(monikai.memories.len(), original_count + 1);
+
+    // Merged memories are slotted in chronologically
+    let mut older_memory = monikai.memories[0].clone();
+    older_memory.timestamp = 0;
+    older_memory.conversation = String::from("The very first conversation.");
+    assert_eq!(monikai.merge_memories(vec![older_memory]), 1);
+    assert_eq!(monikai.memories[0].conversation, "The very first conversation.");
+    assert!(monikai.memories.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+
+    // Inspecting leaves out the embeddings, and only the embeddings
+    let inspected = monikai.without_embeddings();
+    assert!(inspected.memories.iter().all(|memory| memory.embedding.is_empty()));
+    assert_eq!(inspected.memories[0].conversation, monikai.memories[0].conversation);
+
+    let cli = <cli::Cli as clap::Parser>::try_parse_from(["monikaiv2", "import", "backup.json", "--merge", "--character", "data/other.json"])
+        .map_err(|_| ())?;
+    assert!(matches!(cli.command, Some(cli::Command::Import { merge: true, .. })));
+    assert!(cli.character.is_some());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn build_monikai_repl() -> Result<(), ()> {
     // Open the data file
@@ -50,7 +1325,8 @@ async fn build_monikai_repl() -> Result<(), ()> {
         ));
 
     // Start the repl and frontend
-    tokio::spawn(monikai::monikai_repl( monikai.clone() ));
+    let (storage, _storage_requests) = storage::StorageHandle::new();
+    tokio::spawn(monikai::monikai_repl( monikai.clone(), storage, monikai::Shutdown::default() ));
 
     Ok(())
 }
@@ -81,7 +1357,7 @@ async fn build_monikai_backend() -> Result<(), ()> {
         ));
 
     // Start the repl and backend
-    tokio::spawn(monikai::monikai_backend( monikai.clone() ));
+    tokio::spawn(monikai::monikai_backend( monikai.clone(), config::ServerConfig::default(), monikai::Shutdown::default() ));
 
     Ok(())
 }
\ No newline at end of file